@@ -0,0 +1,25 @@
+use std::fs;
+
+/// Expose the version and source of the locked rgb-lib package, recorded in the run metadata
+fn main() {
+    println!("cargo:rerun-if-changed=Cargo.lock");
+    let lock = fs::read_to_string("Cargo.lock").unwrap_or_default();
+    let mut version = "";
+    let mut source = "";
+    for package in lock.split("[[package]]") {
+        let field = |name: &str| {
+            package.lines().find_map(|l| {
+                l.strip_prefix(name)
+                    .and_then(|v| v.trim().strip_prefix('='))
+                    .map(|v| v.trim().trim_matches('"'))
+            })
+        };
+        if field("name ") == Some("rgb-lib") {
+            version = field("version ").unwrap_or_default();
+            // e.g. git+https://github.com/RGB-Tools/rgb-lib?branch=<branch>#<revision>
+            source = field("source ").unwrap_or_default();
+        }
+    }
+    println!("cargo:rustc-env=RGB_LIB_VERSION={version}");
+    println!("cargo:rustc-env=RGB_LIB_SOURCE={source}");
+}
@@ -12,7 +12,7 @@ if ! $COMPOSE >/dev/null; then
     echo "could not call docker compose (hint: install docker compose plugin)"
     exit 1
 fi
-BCLI="$COMPOSE exec -T -u blits bitcoind bitcoin-cli -regtest"
+BCLI="$COMPOSE exec -T -u blits bitcoind bitcoin-cli -regtest -rpcuser=user -rpcpassword=password"
 DATA_DIR="./srv"
 
 start() {
@@ -20,7 +20,7 @@ start() {
     rm -rf $DATA_DIR
     mkdir -p $DATA_DIR
     # see docker-compose.yml for the exposed ports
-    EXPOSED_PORTS=(3000 50001)
+    EXPOSED_PORTS=(3000 18443 50001)
     for port in "${EXPOSED_PORTS[@]}"; do
         if [ -n "$(ss -HOlnt "sport = :$port")" ];then
             _die "port $port is already bound, services can't be started"
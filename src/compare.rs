@@ -0,0 +1,160 @@
+use std::path::Path;
+
+use crate::report::{self, TransferResult};
+use crate::summary::{percentile, step_applies, STEPS};
+
+fn median(values: &mut [f64]) -> f64 {
+    values.sort_by(|a, b| a.total_cmp(b));
+    percentile(values, 50.0)
+}
+
+fn delta_pct(baseline: f64, candidate: f64) -> f64 {
+    if baseline == 0.0 {
+        if candidate == 0.0 {
+            0.0
+        } else {
+            f64::INFINITY
+        }
+    } else {
+        (candidate - baseline) / baseline * 100.0
+    }
+}
+
+/// Compare a candidate report with a baseline one, aligning transfers by their position
+///
+/// Returns an error if the given thresholds (percent increase) are exceeded, if transfers that
+/// succeeded in the baseline failed in the candidate or if the candidate has less transfers.
+pub(crate) fn compare(
+    baseline_path: &Path,
+    candidate_path: &Path,
+    time_threshold: u16,
+    size_threshold: u16,
+) -> Result<(), String> {
+    let (time_threshold, size_threshold) = (time_threshold as f64, size_threshold as f64);
+    let baseline = report::load(baseline_path)?;
+    let candidate = report::load(candidate_path)?;
+    let aligned = baseline.len().min(candidate.len());
+    println!(
+        "comparing {} baseline transfer(s) with {} candidate transfer(s), {aligned} aligned",
+        baseline.len(),
+        candidate.len()
+    );
+    if aligned == 0 {
+        return Err("no transfers to compare".to_string());
+    }
+    let mut violations = vec![];
+    if candidate.len() < baseline.len() {
+        violations.push(format!(
+            "candidate has {} transfer(s) less than the baseline",
+            baseline.len() - candidate.len()
+        ));
+    }
+    let (baseline, candidate) = (&baseline[..aligned], &candidate[..aligned]);
+
+    let mut failed = 0;
+    let mut new_failures = 0;
+    for (i, (b, c)) in baseline.iter().zip(candidate).enumerate() {
+        if !b.succeeded() || !c.succeeded() {
+            failed += 1;
+            if b.succeeded() {
+                new_failures += 1;
+            }
+        } else if b.mode != c.mode || b.donation != c.donation || b.assets.len() != c.assets.len() {
+            println!(
+                "warning: transfer {} differs in send mode, donation or number of assets",
+                i + 1
+            );
+        }
+    }
+    if failed > 0 {
+        println!("skipping {failed} transfer(s) failed in either report");
+    }
+    if new_failures > 0 {
+        violations.push(format!(
+            "{new_failures} transfer(s) failed in the candidate but not in the baseline"
+        ));
+    }
+    // failed transfers are skipped, keeping their position for alignment
+    let both_succeeded =
+        |(_, (b, c)): &(usize, (&TransferResult, &TransferResult))| b.succeeded() && c.succeeded();
+
+    println!(
+        "\nmedian step times (ms)\n{:16} {:>10} {:>10} {:>10} {:>8}",
+        "", "baseline", "candidate", "delta", "delta %"
+    );
+    for (label, time) in STEPS {
+        // medians of the same transfers, so that different failures don't skew the comparison
+        let (mut b_times, mut c_times): (Vec<f64>, Vec<f64>) = baseline
+            .iter()
+            .zip(candidate)
+            .enumerate()
+            .filter(both_succeeded)
+            .filter(|(_, (b, c))| step_applies(label, b) && step_applies(label, c))
+            .map(|(_, (b, c))| (time(&b.times) as f64, time(&c.times) as f64))
+            .unzip();
+        if b_times.is_empty() {
+            println!("{label:16} {:>10}", "n/a");
+            continue;
+        }
+        let b = median(&mut b_times);
+        let c = median(&mut c_times);
+        let pct = delta_pct(b, c);
+        println!(
+            "{label:16} {b:>10.1} {c:>10.1} {:>+10.1} {pct:>+8.1}",
+            c - b
+        );
+        if pct > time_threshold {
+            violations.push(format!(
+                "median {label} time increased by {pct:.1}% (threshold {time_threshold}%)"
+            ));
+        }
+    }
+
+    println!(
+        "\nconsignment size changes (bytes)\n{:>8} {:8} {:>10} {:>10} {:>10} {:>8}",
+        "transfer", "ticker", "baseline", "candidate", "delta", "delta %"
+    );
+    let mut changes = 0;
+    for (i, (b, c)) in baseline
+        .iter()
+        .zip(candidate)
+        .enumerate()
+        .filter(both_succeeded)
+    {
+        for (b_asset, c_asset) in b.assets.iter().zip(&c.assets) {
+            let (b_size, c_size) = (b_asset.consignment_size, c_asset.consignment_size);
+            if b_size == c_size {
+                continue;
+            }
+            changes += 1;
+            let pct = delta_pct(b_size as f64, c_size as f64);
+            println!(
+                "{:>8} {:8} {b_size:>10} {c_size:>10} {:>+10} {pct:>+8.1}",
+                i + 1,
+                c_asset.ticker,
+                c_size as i64 - b_size as i64,
+            );
+            if pct > size_threshold {
+                violations.push(format!(
+                    "transfer {} {} consignment size increased by {pct:.1}% (threshold {size_threshold}%)",
+                    i + 1,
+                    c_asset.ticker
+                ));
+            }
+        }
+    }
+    if changes == 0 {
+        println!("no changes");
+    }
+
+    if violations.is_empty() {
+        println!("\nno thresholds exceeded");
+        Ok(())
+    } else {
+        println!("\nthresholds exceeded:");
+        for violation in &violations {
+            println!("- {violation}");
+        }
+        Err(format!("{} threshold(s) exceeded", violations.len()))
+    }
+}
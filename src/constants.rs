@@ -1,8 +1,15 @@
+pub(crate) const BITCOIND_RPC_URL: &str = "http://localhost:18443";
+pub(crate) const BITCOIND_RPC_USER: &str = "user";
+pub(crate) const BITCOIND_RPC_PASSWORD: &str = "password";
 pub(crate) const ELECTRUM_URL: &str = "tcp://localhost:50001";
 pub(crate) const FEE_AMT: u32 = 200;
 pub(crate) const FEE_RATE: f32 = 1.5;
 pub(crate) const TRANSPORT_ENDPOINT: &str = "rpc://localhost:3000/json-rpc";
-pub(crate) const MIN_CONFIRMATIONS: u8 = 1;
+pub(crate) const DEFAULT_MIN_CONFIRMATIONS: u8 = 1;
 pub(crate) const DEFAULT_MAX_ALLOCATIONS_PER_UTXO: u32 = 5;
 pub(crate) const MIN_TX_SATS: u32 = 294;
 pub(crate) const WITNESS_SATS: u32 = 1000;
+pub(crate) const DEFAULT_MEDIA_SIZE: u32 = 1024;
+pub(crate) const RETRY_DELAY: std::time::Duration = std::time::Duration::from_secs(1);
+pub(crate) const BACKUP_PASSWORD: &str = "password";
+pub(crate) const SOAK_STATS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(300);
@@ -1,29 +1,282 @@
+mod compare;
 mod constants;
+mod manifest;
+mod metadata;
 mod opts;
 mod regtest;
+mod report;
+mod restore;
 mod rgb;
+mod scenario_file;
 mod scenarios;
+mod soak;
+mod summary;
+mod sweep;
+mod trace;
 
+use std::collections::BTreeMap;
 use std::fs;
 
 use clap::Parser;
-use constants::{MIN_TX_SATS, WITNESS_SATS};
-use scenarios::{merge_histories, merge_utxos, random_transfers, random_wallets};
+use constants::{MIN_TX_SATS, SOAK_STATS_INTERVAL, WITNESS_SATS};
+use scenarios::{
+    batch_block, batch_send, mempool_chain, merge_histories, merge_utxos, random_transfers,
+    random_wallets, reorg,
+};
 
+use crate::metadata::RunMetadata;
 use crate::opts::Opts;
+use crate::report::Report;
+use crate::scenario_file::ScenarioFile;
 use crate::scenarios::send_loop;
+use crate::sweep::SweepRun;
 
 fn main() -> Result<(), String> {
     // setup
-    let opts = Opts::parse();
-    if !matches!(opts.command, crate::opts::Command::RandomTransfers { .. })
-        && opts.allocation_utxos == 1
+    let mut opts = Opts::parse();
+    if let opts::Command::Compare {
+        baseline,
+        candidate,
+        time_threshold,
+        size_threshold,
+    } = &opts.command
     {
+        return compare::compare(baseline, candidate, *time_threshold, *size_threshold);
+    }
+    let run_args = manifest::run_args();
+    let mut runs = match &opts.command {
+        opts::Command::Sweep {
+            params,
+            repeats,
+            scenario,
+        } => sweep::runs(&run_args, params, *repeats, scenario)?,
+        // repetitions intentionally share the seed, so that they make the same random choices and
+        // the transfers at each position can be compared across them
+        _ => (0..opts.repeat)
+            .map(|_| SweepRun {
+                params: BTreeMap::new(),
+                repeat: 0,
+                opts: opts.clone(),
+            })
+            .collect(),
+    };
+    let mut scenario_files = Vec::with_capacity(runs.len());
+    for run in &runs {
+        let scenario_file = match &run.opts.command {
+            opts::Command::Run { file } => Some(scenario_file::load(file, run.opts.schema)?),
+            _ => None,
+        };
+        check(&run.opts, scenario_file.as_ref())?;
+        scenario_files.push(scenario_file);
+    }
+    let sweeping = matches!(opts.command, opts::Command::Sweep { .. });
+    let repeating = opts.repeat > 1;
+    if repeating && sweeping {
+        return Err("'--repeat' is not supported by sweep, use its '--repeats' option".to_string());
+    }
+    if opts.warmup >= opts.repeat {
+        return Err(format!(
+            "invalid value '{}' for '--warmup <WARMUP>': must be lower than '--repeat'",
+            opts.warmup
+        ));
+    }
+    if opts.resume && sweeping {
+        return Err("'--resume' is not supported by sweep".to_string());
+    }
+    if opts.resume && repeating {
+        return Err("'--resume' is not supported with '--repeat'".to_string());
+    }
+    if opts.resume && opts.concurrency > 1 {
+        return Err("'--resume' is not supported with '--concurrency'".to_string());
+    }
+    if !opts.force && !opts.resume && opts.output.exists() {
+        return Err(
+            "Report file already exists, abrting. (run with --force to override)".to_string(),
+        );
+    }
+    if opts.resume {
+        opts.seed = Some(manifest::resume(&opts.data_dir, &run_args)?);
+    }
+    let seed = *opts.seed.get_or_insert_with(rand::random);
+    println!("seed: {seed}");
+    regtest::handle_interrupts(report::has_unflushed);
+    regtest::init_bitcoind(
+        &opts.bitcoind_rpc_url,
+        &opts.bitcoind_rpc_user,
+        &opts.bitcoind_rpc_password,
+    );
+    regtest::init_endpoints(&opts.electrum_url, &opts.transport_endpoint);
+    let services = if opts.external_services {
+        None
+    } else {
+        Some(regtest::ServicesGuard::start(opts.keep_services_on_failure))
+    };
+    regtest::check_services()?;
+    let data_dir = opts.data_dir.to_str().unwrap();
+    fs::create_dir_all(data_dir).unwrap();
+    let metadata = RunMetadata::start(&opts.output, &opts, run_args.clone(), seed);
+    let mut report = if opts.resume {
+        Report::resume(&opts.output, opts.format, seed, opts.on_error)?
+    } else {
+        manifest::start(&opts.data_dir, run_args, seed);
+        Report::new(&opts.output, opts.format, seed, opts.on_error)
+    };
+    if opts.trace {
+        trace::init();
+    }
+    if let Some(duration) = opts.duration {
+        println!("running for {}", soak::format_duration(duration));
+        report.monitor(SOAK_STATS_INTERVAL);
+    }
+
+    // command processing
+    let summary_path = opts.summary.clone();
+    let restore = opts.restore.then(|| data_dir.to_string());
+    let verify_backups = opts.backup_every.map(|_| data_dir.to_string());
+    let num_runs = runs.len();
+    for (i, (mut run, scenario_file)) in runs.drain(..).zip(scenario_files).enumerate() {
+        // sweep repeats draw different random choices, parameter combinations and repetitions the
+        // same ones
+        run.opts.seed = Some(seed.wrapping_add(run.repeat as u64));
+        if repeating {
+            let warmup = opts.warmup as usize;
+            if i < warmup {
+                println!("\nwarm-up repetition {}/{warmup}", i + 1);
+            } else {
+                println!("\nrepetition {}/{}", i + 1 - warmup, num_runs - warmup);
+                let repeat = (i + 1 - warmup).to_string();
+                report.set_params(BTreeMap::from([("repeat".to_string(), repeat)]));
+            }
+            report.set_warmup(i < warmup);
+        }
+        if sweeping {
+            println!(
+                "\nsweep run {}/{num_runs}: {}",
+                i + 1,
+                sweep::format_params(&run.params)
+            );
+            report.set_params(run.params);
+        }
+        run_scenario(run.opts, &mut report, scenario_file);
+    }
+    report.flush();
+    if opts.trace {
+        trace::write_folded(&opts.output.with_extension("folded"));
+    }
+
+    // summary
+    let mut summary = summary::summarize(report.transfers());
+    if sweeping {
+        summary.push('\n');
+        summary.push_str(&summary::summarize_sweep(report.transfers()));
+    }
+    if repeating {
+        summary.push('\n');
+        summary.push_str(&summary::summarize_repetitions(report.transfers()));
+    }
+    if let Some(data_dir) = restore {
+        let wallets = scenarios::created_wallets();
+        summary.push('\n');
+        summary.push_str(&restore::restore_wallets(&data_dir, &wallets));
+    }
+    if let Some(data_dir) = verify_backups {
+        let wallets = scenarios::created_wallets();
+        summary.push('\n');
+        summary.push_str(&restore::verify_backups(&data_dir, &wallets));
+    }
+    println!("\n{summary}");
+    if let Some(path) = summary_path {
+        fs::write(path, summary).expect("summary file should have been written");
+    }
+
+    metadata.finish();
+
+    // teardown
+    if let Some(services) = services {
+        services.stop();
+    }
+    Ok(())
+}
+
+/// Check the options of a scenario run, including the ones clap cannot validate on its own
+fn check(opts: &Opts, scenario_file: Option<&ScenarioFile>) -> Result<(), String> {
+    let handles_utxo_errors = match scenario_file {
+        Some(scenario) => scenario.handle_utxo_errors,
+        None => {
+            matches!(
+                opts.command,
+                crate::opts::Command::RandomTransfers { .. }
+                    | crate::opts::Command::BatchBlock { .. }
+                    | crate::opts::Command::MempoolChain { .. }
+                    | crate::opts::Command::Reorg { .. }
+                    | crate::opts::Command::BatchSend { .. }
+            ) || opts.duration.is_some()
+        }
+    };
+    if !handles_utxo_errors && opts.allocation_utxos == 1 {
         return Err(
             "invalid value '1' for '--allocation_utxos <ALLOCATION_UTXOS>': valid range 2..255"
                 .to_string(),
         );
     }
+    if opts.schema == opts::Schema::Uda {
+        match opts.command {
+            opts::Command::MergeHistories { .. } => {
+                return Err("invalid value 'uda' for '--schema <SCHEMA>': \
+                    merge-histories issues 2 allocations"
+                    .to_string());
+            }
+            opts::Command::BatchSend { .. } => {
+                return Err("invalid value 'uda' for '--schema <SCHEMA>': \
+                    batch-send pays multiple recipients"
+                    .to_string());
+            }
+            opts::Command::BatchBlock { loops, .. } if loops > 1 => {
+                return Err("invalid value 'uda' for '--schema <SCHEMA>': \
+                    batch-block issues `loops` allocations"
+                    .to_string());
+            }
+            _ => {}
+        }
+    }
+    if matches!(opts.command, opts::Command::MempoolChain { .. }) && opts.min_confirmations > 0 {
+        return Err(format!(
+            "invalid value '{}' for '--min-confirmations <MIN_CONFIRMATIONS>': \
+            mempool-chain spends unconfirmed allocations, so it must be 0",
+            opts.min_confirmations
+        ));
+    }
+    if opts.duration.is_some()
+        && !matches!(
+            opts.command,
+            opts::Command::SendLoop { .. }
+                | opts::Command::RandomWallets { .. }
+                | opts::Command::RandomTransfers { .. }
+        )
+    {
+        return Err(
+            "'--duration' is only supported by send-loop, random-wallets and random-transfers"
+                .to_string(),
+        );
+    }
+    if opts.concurrency > 1 {
+        match opts.command {
+            opts::Command::SendLoop { .. } if opts.concurrency <= 127 => {}
+            opts::Command::RandomWallets { wallets, .. }
+                if opts.concurrency as u16 * wallets as u16 <= 255 => {}
+            opts::Command::SendLoop { .. } | opts::Command::RandomWallets { .. } => {
+                return Err(format!(
+                    "invalid value '{}' for '--concurrency <CONCURRENCY>': too many wallets",
+                    opts.concurrency
+                ));
+            }
+            _ => {
+                return Err(
+                    "'--concurrency' is only supported by send-loop and random-wallets".to_string(),
+                );
+            }
+        }
+    }
     if opts.witness && (opts.utxo_size < (WITNESS_SATS + MIN_TX_SATS)) {
         return Err(format!(
             "invalid value '{}' for '--utxo-size <UTXO_SIZE>': \
@@ -32,30 +285,51 @@ fn main() -> Result<(), String> {
             WITNESS_SATS + MIN_TX_SATS
         ));
     }
-    if !opts.force && opts.output.exists() {
-        return Err(
-            "Report file already exists, abrting. (run with --force to override)".to_string(),
-        );
-    }
-    regtest::start_services();
-    let data_dir = opts.data_dir.to_str().unwrap();
-    fs::create_dir_all(data_dir).unwrap();
+    Ok(())
+}
 
-    // command processing
+fn run_scenario(opts: Opts, report: &mut Report, scenario_file: Option<ScenarioFile>) {
     match opts.command {
-        opts::Command::SendLoop { loops } => send_loop(opts, loops),
-        opts::Command::MergeHistories { loops } => merge_histories(opts, loops),
-        opts::Command::MergeUtxos { assets, loops } => merge_utxos(opts, assets, loops),
-        opts::Command::RandomWallets { loops, wallets } => random_wallets(opts, loops, wallets),
+        opts::Command::SendLoop { loops } => send_loop(opts, report, loops),
+        opts::Command::MergeHistories { loops } => merge_histories(opts, report, loops),
+        opts::Command::MergeUtxos { assets, loops } => merge_utxos(opts, report, assets, loops),
+        opts::Command::RandomWallets { loops, wallets } => {
+            random_wallets(opts, report, loops, wallets)
+        }
         opts::Command::RandomTransfers {
             wallets,
             assets,
             max_allocations_per_utxo,
             loops,
-        } => random_transfers(opts, wallets, assets, max_allocations_per_utxo, loops),
+        } => random_transfers(
+            opts,
+            report,
+            wallets,
+            assets,
+            max_allocations_per_utxo,
+            loops,
+        ),
+        opts::Command::BatchBlock { senders, loops } => batch_block(opts, report, senders, loops),
+        opts::Command::MempoolChain { wallets, loops } => {
+            mempool_chain(opts, report, wallets, loops)
+        }
+        opts::Command::Reorg {
+            conflict,
+            blocks,
+            loops,
+        } => reorg(opts, report, conflict, blocks, loops),
+        opts::Command::BatchSend {
+            assets,
+            recipients_per_asset,
+            loops,
+        } => batch_send(opts, report, assets, recipients_per_asset, loops),
+        opts::Command::Run { .. } => scenario_file::run(
+            opts,
+            report,
+            scenario_file.expect("scenario file should be loaded"),
+        ),
+        opts::Command::Sweep { .. } | opts::Command::Compare { .. } => {
+            unreachable!("not a scenario")
+        }
     };
-
-    // teardown
-    regtest::stop_services();
-    Ok(())
 }
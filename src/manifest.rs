@@ -0,0 +1,248 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::report::{TransferFailure, TransferResult};
+
+const MANIFEST_FILE: &str = "manifest.json";
+
+/// Wallet of the run, with the data needed to reopen it
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ManifestWallet {
+    pub(crate) wallet_index: u8,
+    pub(crate) fingerprint: String,
+    pub(crate) mnemonic: String,
+    pub(crate) pubkey: String,
+    pub(crate) max_allocations_per_utxo: u32,
+    /// Number of succeeded transfers the wallet took part in, numbering its backups
+    #[serde(default)]
+    pub(crate) transfers: u32,
+}
+
+/// Asset issued during the run
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ManifestAsset {
+    pub(crate) asset_id: String,
+    pub(crate) ticker: String,
+    /// Asset schema ("nia", "cfa" or "uda")
+    pub(crate) schema: String,
+}
+
+/// Run manifest, persisted in the data dir so an interrupted run can be resumed
+///
+/// Wallets are recorded once funded, in setup order, and assets in issuance order. The RNG state
+/// is rebuilt from the seed, as resumed scenarios replay their random choices up to the last
+/// completed transfer, with the outcome each transfer had. Random transfers draw from their own RNG (see `transfer_rng`), so their
+/// choices do not depend on the wallet balances met by the replayed transfers.
+#[derive(Serialize, Deserialize, Debug)]
+struct Manifest {
+    /// Command-line arguments of the run, excluding `--resume` and `--force`
+    args: Vec<String>,
+    seed: u64,
+    wallets: Vec<ManifestWallet>,
+    assets: Vec<ManifestAsset>,
+    /// Number of transfers written to the report
+    completed_transfers: u64,
+    /// Failures of the completed transfers, by transfer number
+    #[serde(default)]
+    failures: BTreeMap<u64, TransferFailure>,
+    /// Time of the `--duration` budget spent up to the last completed transfer
+    #[serde(default)]
+    soak_elapsed: Duration,
+}
+
+/// Progress of a resumed run replaying what the manifest records
+#[derive(Default)]
+struct Replay {
+    wallets: usize,
+    assets: usize,
+    transfers: u64,
+}
+
+struct ManifestState {
+    path: PathBuf,
+    manifest: Manifest,
+    replay: Replay,
+    /// Start of the `--duration` budget in this execution, with the time spent before it
+    soak: Option<(Instant, Duration)>,
+}
+
+impl ManifestState {
+    fn save(&self) {
+        // write a temporary file then rename it, so the manifest is never left half-written
+        let tmp_path = self.path.with_extension("json.tmp");
+        let json = serde_json::to_string_pretty(&self.manifest).unwrap();
+        fs::write(&tmp_path, json).expect("manifest should have been written");
+        fs::rename(&tmp_path, &self.path).expect("manifest should have been renamed");
+    }
+}
+
+static MANIFEST: Mutex<Option<ManifestState>> = Mutex::new(None);
+
+/// Command-line arguments identifying the run
+pub(crate) fn run_args() -> Vec<String> {
+    std::env::args()
+        .skip(1)
+        .filter(|a| !matches!(a.as_str(), "--resume" | "--force" | "-f"))
+        .collect()
+}
+
+/// Start recording a new run, replacing any previous manifest in the data dir
+pub(crate) fn start(data_dir: &Path, args: Vec<String>, seed: u64) {
+    let state = ManifestState {
+        path: data_dir.join(MANIFEST_FILE),
+        manifest: Manifest {
+            args,
+            seed,
+            wallets: vec![],
+            assets: vec![],
+            completed_transfers: 0,
+            failures: BTreeMap::new(),
+            soak_elapsed: Duration::ZERO,
+        },
+        replay: Replay::default(),
+        soak: None,
+    };
+    state.save();
+    *MANIFEST.lock().unwrap() = Some(state);
+}
+
+/// Load the manifest of an interrupted run from the data dir, returning its seed
+pub(crate) fn resume(data_dir: &Path, args: &[String]) -> Result<u64, String> {
+    let path = data_dir.join(MANIFEST_FILE);
+    let content = fs::read_to_string(&path)
+        .map_err(|e| format!("cannot read run manifest '{}': {e}", path.display()))?;
+    let manifest: Manifest = serde_json::from_str(&content)
+        .map_err(|e| format!("invalid run manifest '{}': {e}", path.display()))?;
+    if manifest.args != args {
+        return Err(format!(
+            "cannot resume with different arguments, the run was started with: {}",
+            manifest.args.join(" ")
+        ));
+    }
+    let seed = manifest.seed;
+    println!(
+        "resuming run after {} completed transfer(s)",
+        manifest.completed_transfers
+    );
+    *MANIFEST.lock().unwrap() = Some(ManifestState {
+        path,
+        manifest,
+        replay: Replay::default(),
+        soak: None,
+    });
+    Ok(seed)
+}
+
+/// Next wallet to be reopened, if the run is being resumed and the wallet was already set up
+pub(crate) fn replay_wallet(wallet_index: u8) -> Option<ManifestWallet> {
+    let mut guard = MANIFEST.lock().unwrap();
+    let state = guard.as_mut()?;
+    let wallet = state.manifest.wallets.get(state.replay.wallets)?.clone();
+    assert_eq!(
+        wallet.wallet_index, wallet_index,
+        "resumed scenario should set up wallets in the recorded order"
+    );
+    state.replay.wallets += 1;
+    Some(wallet)
+}
+
+pub(crate) fn record_wallet(wallet: ManifestWallet) {
+    if let Some(state) = MANIFEST.lock().unwrap().as_mut() {
+        state.manifest.wallets.push(wallet);
+        state.replay.wallets += 1;
+        state.save();
+    }
+}
+
+/// Next asset to be reused instead of issued, if the run is being resumed and it was issued
+pub(crate) fn replay_asset() -> Option<ManifestAsset> {
+    let mut guard = MANIFEST.lock().unwrap();
+    let state = guard.as_mut()?;
+    let asset = state.manifest.assets.get(state.replay.assets)?.clone();
+    state.replay.assets += 1;
+    Some(asset)
+}
+
+pub(crate) fn record_asset(asset: ManifestAsset) {
+    if let Some(state) = MANIFEST.lock().unwrap().as_mut() {
+        state.manifest.assets.push(asset);
+        state.replay.assets += 1;
+        state.save();
+    }
+}
+
+/// Number of transfers recorded as completed, if the run is recorded in a manifest
+pub(crate) fn completed_transfers() -> Option<u64> {
+    MANIFEST
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|state| state.manifest.completed_transfers)
+}
+
+/// Outcome of the given number of transfers, if they were completed before the run was
+/// interrupted, in which case they are skipped
+///
+/// Each transfer yields its failure, so that the resumed run takes the same decisions on it.
+pub(crate) fn replay_transfers(count: usize) -> Option<Vec<Option<TransferFailure>>> {
+    let mut guard = MANIFEST.lock().unwrap();
+    let state = guard.as_mut()?;
+    let first = state.replay.transfers;
+    let replayed = first + count as u64;
+    if replayed > state.manifest.completed_transfers {
+        return None;
+    }
+    state.replay.transfers = replayed;
+    Some(
+        (first..replayed)
+            .map(|i| state.manifest.failures.get(&i).cloned())
+            .collect(),
+    )
+}
+
+/// Record transfers carried out together (e.g. a batch) as completed, all at once so that a
+/// resumed run never replays part of them
+pub(crate) fn record_transfers(results: &[TransferResult]) {
+    if let Some(state) = MANIFEST.lock().unwrap().as_mut() {
+        for result in results {
+            if let Some(failure) = &result.failure {
+                let number = state.manifest.completed_transfers;
+                state.manifest.failures.insert(number, failure.clone());
+            } else {
+                for wallet in state.manifest.wallets.iter_mut() {
+                    if wallet.fingerprint == result.sender || wallet.fingerprint == result.receiver
+                    {
+                        wallet.transfers += 1;
+                    }
+                }
+            }
+            state.manifest.completed_transfers += 1;
+            state.replay.transfers += 1;
+        }
+        if let Some((start, spent)) = state.soak {
+            state.manifest.soak_elapsed = spent + start.elapsed();
+        }
+        state.save();
+    }
+}
+
+/// Start the `--duration` budget of a scenario, returning the time of it already spent
+///
+/// Only the first budget of the execution of a resumed run continues the one of the interrupted
+/// execution, later ones (e.g. of repetitions) start from scratch.
+pub(crate) fn start_soak() -> Duration {
+    let mut guard = MANIFEST.lock().unwrap();
+    let Some(state) = guard.as_mut() else {
+        return Duration::ZERO;
+    };
+    let spent = match state.soak {
+        Some(_) => Duration::ZERO,
+        None => state.manifest.soak_elapsed,
+    };
+    state.soak = Some((Instant::now(), spent));
+    spent
+}
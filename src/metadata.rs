@@ -0,0 +1,181 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::opts::Opts;
+
+const COMPOSE_FILE: &str = "docker-compose.yml";
+
+/// Locked rgb-lib package
+#[derive(Serialize, Deserialize, Debug)]
+struct RgbLib {
+    version: String,
+    /// Package source, e.g. the git repository and branch of the patched rgb-lib
+    source: String,
+    /// Git revision, if rgb-lib comes from a git repository
+    revision: Option<String>,
+}
+
+impl RgbLib {
+    fn locked() -> Self {
+        let source = env!("RGB_LIB_SOURCE");
+        let (source, revision) = match source.split_once('#') {
+            Some((source, revision)) => (source, Some(revision.to_string())),
+            None => (source, None),
+        };
+        RgbLib {
+            version: env!("RGB_LIB_VERSION").to_string(),
+            source: source.to_string(),
+            revision,
+        }
+    }
+}
+
+/// Machine the run was executed on
+#[derive(Serialize, Deserialize, Debug)]
+struct Host {
+    os: String,
+    arch: String,
+    cpu: Option<String>,
+    cpus: usize,
+    /// Total memory, in bytes
+    memory: Option<u64>,
+}
+
+impl Host {
+    fn current() -> Self {
+        let cpu = fs::read_to_string("/proc/cpuinfo").ok().and_then(|info| {
+            info.lines()
+                .find(|l| l.starts_with("model name"))
+                .and_then(|l| l.split_once(':'))
+                .map(|(_, model)| model.trim().to_string())
+        });
+        let memory = fs::read_to_string("/proc/meminfo").ok().and_then(|info| {
+            info.lines()
+                .find_map(|l| l.strip_prefix("MemTotal:"))
+                .and_then(|kb| kb.trim().trim_end_matches("kB").trim().parse::<u64>().ok())
+                .map(|kb| kb * 1024)
+        });
+        Host {
+            os: std::env::consts::OS.to_string(),
+            arch: std::env::consts::ARCH.to_string(),
+            cpu,
+            cpus: std::thread::available_parallelism().map_or(1, |n| n.get()),
+            memory,
+        }
+    }
+}
+
+/// Metadata of a run, written next to the report so it can be interpreted later on
+#[derive(Serialize, Deserialize, Debug)]
+pub(crate) struct RunMetadata {
+    #[serde(skip)]
+    path: PathBuf,
+    /// Command-line arguments of the run
+    args: Vec<String>,
+    /// Parsed options, including the command (the bitcoind RPC password is omitted)
+    opts: serde_json::Value,
+    rgb_lib: RgbLib,
+    seed: u64,
+    start: String,
+    /// Start of each resumed execution
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    resumed: Vec<String>,
+    /// End of the run, unset if the run failed or was interrupted
+    end: Option<String>,
+    host: Host,
+    /// Image of each docker service, by service name
+    images: BTreeMap<String, String>,
+}
+
+impl RunMetadata {
+    /// Write the metadata of a run starting now
+    ///
+    /// A resumed run keeps the start of the interrupted run, adding the time it was resumed.
+    pub(crate) fn start(report_path: &Path, opts: &Opts, args: Vec<String>, seed: u64) -> Self {
+        let path = report_path.with_extension("meta.json");
+        let now = timestamp(SystemTime::now());
+        let previous = opts
+            .resume
+            .then(|| fs::read_to_string(&path).ok())
+            .flatten()
+            .and_then(|content| serde_json::from_str::<RunMetadata>(&content).ok());
+        let (start, resumed) = match previous {
+            Some(previous) => {
+                let mut resumed = previous.resumed;
+                resumed.push(now);
+                (previous.start, resumed)
+            }
+            None => (now, vec![]),
+        };
+        let metadata = RunMetadata {
+            path,
+            args,
+            opts: serde_json::to_value(opts).unwrap(),
+            rgb_lib: RgbLib::locked(),
+            seed,
+            start,
+            resumed,
+            end: None,
+            host: Host::current(),
+            images: compose_images(),
+        };
+        metadata.write();
+        metadata
+    }
+
+    /// Record the end of the run
+    pub(crate) fn finish(mut self) {
+        self.end = Some(timestamp(SystemTime::now()));
+        self.write();
+    }
+
+    fn write(&self) {
+        let json = serde_json::to_string_pretty(self).unwrap();
+        fs::write(&self.path, json).expect("metadata file should have been written");
+    }
+}
+
+/// Image of each service of the docker compose file, if found in the current directory
+fn compose_images() -> BTreeMap<String, String> {
+    let Ok(content) = fs::read_to_string(COMPOSE_FILE) else {
+        return BTreeMap::new();
+    };
+    let compose: serde_yaml::Value = serde_yaml::from_str(&content).unwrap_or_default();
+    let Some(services) = compose.get("services").and_then(|s| s.as_mapping()) else {
+        return BTreeMap::new();
+    };
+    services
+        .iter()
+        .filter_map(|(name, service)| {
+            Some((
+                name.as_str()?.to_string(),
+                service.get("image")?.as_str()?.to_string(),
+            ))
+        })
+        .collect()
+}
+
+/// Format a time as an RFC 3339 UTC timestamp, e.g. `2024-02-01T12:30:00Z`
+fn timestamp(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let (days, secs) = (secs / 86400, secs % 86400);
+    // civil date from days since the epoch (Howard Hinnant's algorithm)
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}Z",
+        secs / 3600,
+        secs % 3600 / 60,
+        secs % 60
+    )
+}
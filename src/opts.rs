@@ -1,10 +1,21 @@
+use std::fmt;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
+use rgb_lib::AssetSchema;
+use serde::{Deserialize, Serialize, Serializer};
 
-use crate::constants::{DEFAULT_MAX_ALLOCATIONS_PER_UTXO, MIN_TX_SATS, WITNESS_SATS};
+use crate::constants::{
+    BITCOIND_RPC_PASSWORD, BITCOIND_RPC_URL, BITCOIND_RPC_USER, DEFAULT_MAX_ALLOCATIONS_PER_UTXO,
+    DEFAULT_MEDIA_SIZE, DEFAULT_MIN_CONFIRMATIONS, ELECTRUM_URL, MIN_TX_SATS, TRANSPORT_ENDPOINT,
+    WITNESS_SATS,
+};
+use crate::soak::parse_duration;
+use crate::sweep::SweepParam;
 
-#[derive(Parser, Clone, PartialEq, Eq, Debug)]
+#[derive(Parser, Serialize, Clone, PartialEq, Eq, Debug)]
 #[clap(name = "rgb_lib_stress_test", bin_name = "rgb_lib_stress_test")]
 pub struct Opts {
     /// Override output file existence check
@@ -30,10 +41,32 @@ pub struct Opts {
     #[arg(value_parser = clap::value_parser!(u64).range(1..))]
     pub send_amount: u64,
 
-    /// CSV report file path
+    /// Report file path
     #[clap(short, long, default_value = "report.csv")]
     pub output: PathBuf,
 
+    /// Report file format
+    #[clap(long, value_enum, default_value_t = ReportFormat::Csv)]
+    pub format: ReportFormat,
+
+    /// Also write the end-of-run summary to this file
+    #[clap(long)]
+    pub summary: Option<PathBuf>,
+
+    /// Schema of the issued assets
+    #[clap(long, value_enum, default_value_t = Schema::Nia)]
+    pub schema: Schema,
+
+    /// Size, in bytes, of the media file attached to CFA assets (0 for no media)
+    #[clap(long, default_value_t = DEFAULT_MEDIA_SIZE)]
+    pub media_size: u32,
+
+    /// Number of transfers run in parallel, each by a worker thread with its own wallets
+    /// (send-loop and random-wallets only)
+    #[clap(long, default_value_t = 1)]
+    #[arg(value_parser = clap::value_parser!(u8).range(1..))]
+    pub concurrency: u8,
+
     /// Enable verbose output
     #[clap(short, long, action)]
     pub verbose: bool,
@@ -42,11 +75,182 @@ pub struct Opts {
     #[clap(short, long, action)]
     pub witness: bool,
 
+    /// Use already-running services instead of starting and stopping them in docker
+    #[clap(long, action)]
+    pub external_services: bool,
+
+    /// Leave services running if the execution fails or is interrupted, for debugging
+    #[clap(long, action)]
+    pub keep_services_on_failure: bool,
+
+    /// Electrum server URL
+    #[clap(long, env = "ELECTRUM_URL", default_value = ELECTRUM_URL)]
+    pub electrum_url: String,
+
+    /// RGB proxy server transport endpoint
+    #[clap(long, env = "TRANSPORT_ENDPOINT", default_value = TRANSPORT_ENDPOINT)]
+    pub transport_endpoint: String,
+
+    /// bitcoind JSON-RPC URL
+    #[clap(long, env = "BITCOIND_RPC_URL", default_value = BITCOIND_RPC_URL)]
+    pub bitcoind_rpc_url: String,
+
+    /// bitcoind JSON-RPC user
+    #[clap(long, env = "BITCOIND_RPC_USER", default_value = BITCOIND_RPC_USER)]
+    pub bitcoind_rpc_user: String,
+
+    /// bitcoind JSON-RPC password
+    #[clap(long, env = "BITCOIND_RPC_PASSWORD", default_value = BITCOIND_RPC_PASSWORD)]
+    #[serde(skip)]
+    pub bitcoind_rpc_password: String,
+
+    /// Send transfers as donations, broadcasting them without waiting for the receiver ACK
+    #[clap(long)]
+    pub donation: bool,
+
+    /// Confirmations required for transfers to settle (0 to spend unconfirmed allocations)
+    #[clap(long, default_value_t = DEFAULT_MIN_CONFIRMATIONS)]
+    pub min_confirmations: u8,
+
+    /// What to do when a transfer fails: abort, continue or retry:N (retry each failed step up to
+    /// N times, then continue)
+    #[clap(long, default_value = "abort")]
+    pub on_error: OnError,
+
+    /// Back up each wallet every given number of succeeded transfers it takes part in, then
+    /// verify the latest backup of each wallet restores the assets and transfers it was taken
+    /// with at the end of the run (1-65535)
+    #[clap(long)]
+    #[arg(value_parser = clap::value_parser!(u16).range(1..))]
+    pub backup_every: Option<u16>,
+
+    /// After the scenario, restore each wallet from its mnemonic in a fresh data dir and compare
+    /// it with the original one
+    #[clap(long)]
+    pub restore: bool,
+
+    /// Resume an interrupted run from the manifest in the data dir, reopening its wallets and
+    /// appending to its report (requires the same arguments as the interrupted run)
+    #[clap(long)]
+    pub resume: bool,
+
+    /// Keep the scenario loop running until the given time is spent (e.g. 30m or 8h) instead of
+    /// running `loops` loops, printing rolling throughput and step times periodically (send-loop,
+    /// random-wallets and random-transfers only)
+    #[clap(long, value_parser = parse_duration)]
+    pub duration: Option<Duration>,
+
+    /// Run the scenario the given number of times, each with fresh wallets and the same seed,
+    /// and summarize timings across repetitions (1-255)
+    #[clap(long, default_value_t = 1)]
+    #[arg(value_parser = clap::value_parser!(u8).range(1..))]
+    pub repeat: u8,
+
+    /// Number of initial repetitions to discard as warm-up, not written to the report
+    #[clap(long, default_value_t = 0)]
+    pub warmup: u8,
+
+    /// Capture tracing spans around each step, wallet operation and bitcoind or electrum request
+    /// (and any emitted by rgb-lib), writing their self time as folded stacks next to the report
+    #[clap(long)]
+    pub trace: bool,
+
+    /// Seed for random choices (randomly generated if not given)
+    #[clap(long)]
+    pub seed: Option<u64>,
+
     #[clap(subcommand)]
     pub command: Command,
 }
 
-#[derive(Subcommand, Clone, Eq, PartialEq, Debug)]
+#[derive(ValueEnum, Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum ReportFormat {
+    /// Comma-separated values, one line per transfer
+    Csv,
+    /// A single JSON document
+    Json,
+    /// Newline-delimited JSON, one object per transfer
+    Ndjson,
+}
+
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Schema {
+    /// Non-inflatable assets
+    Nia,
+    /// Collectible fungible assets, with a media file
+    Cfa,
+    /// Unique digital assets, always sent with an amount of 1
+    Uda,
+    /// Cycle through NIA, CFA and UDA for successive issuances (UDA only for single-allocation
+    /// issuances, NIA otherwise)
+    Mixed,
+}
+
+impl Schema {
+    /// Schema of the issuance with the given index, having the given number of allocations (or
+    /// split between that number of recipients)
+    pub fn for_issuance(&self, index: usize, allocations: usize) -> AssetSchema {
+        match self {
+            Schema::Nia => AssetSchema::Nia,
+            Schema::Cfa => AssetSchema::Cfa,
+            Schema::Uda => AssetSchema::Uda,
+            Schema::Mixed => match index % 3 {
+                1 => AssetSchema::Cfa,
+                2 if allocations == 1 => AssetSchema::Uda,
+                _ => AssetSchema::Nia,
+            },
+        }
+    }
+}
+
+/// Behavior when a transfer fails
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum OnError {
+    /// Record the failed transfer and stop the execution
+    Abort,
+    /// Record the failed transfer and proceed with the scenario
+    Continue,
+    /// Retry each failed step up to the given number of times, then continue
+    Retry(u8),
+}
+
+impl FromStr for OnError {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "abort" => Ok(OnError::Abort),
+            "continue" => Ok(OnError::Continue),
+            _ => match s.strip_prefix("retry:").map(str::parse::<u8>) {
+                Some(Ok(retries)) if retries > 0 => Ok(OnError::Retry(retries)),
+                _ => Err(format!(
+                    "invalid value '{s}', expected abort, continue or retry:N (N in 1-255)"
+                )),
+            },
+        }
+    }
+}
+
+impl fmt::Display for OnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OnError::Abort => write!(f, "abort"),
+            OnError::Continue => write!(f, "continue"),
+            OnError::Retry(retries) => write!(f, "retry:{retries}"),
+        }
+    }
+}
+
+impl Serialize for OnError {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[derive(Subcommand, Serialize, Clone, Eq, PartialEq, Debug)]
+#[serde(rename_all = "kebab-case")]
 pub enum Command {
     /// Send assets back and forth between 2 wallets `loops` times
     SendLoop {
@@ -115,4 +319,118 @@ pub enum Command {
         #[arg(value_parser = clap::value_parser!(u8).range(2..))]
         wallets: u8,
     },
+
+    /// Issue an asset (`loops` allocations) to each of `senders` wallets, then `loops` times send
+    /// an allocation from each sender to a common receiver, confirming all transfers with a
+    /// single block
+    BatchBlock {
+        /// Number of senders, i.e. transfers per block (2-255)
+        #[clap(short, long, default_value_t = 4)]
+        #[arg(value_parser = clap::value_parser!(u8).range(2..))]
+        senders: u8,
+
+        /// Number of loops (1-65535)
+        #[clap(short, long, default_value_t = 4)]
+        #[arg(value_parser = clap::value_parser!(u16).range(1..))]
+        loops: u16,
+    },
+
+    /// Issue an asset to the first of `wallets` wallets, then `loops` times pass it through all
+    /// of them and back to the first one without mining between hops, confirming the whole chain
+    /// with a single block (requires `--min-confirmations 0`)
+    MempoolChain {
+        /// Number of wallets, i.e. hops of each chain (2-255)
+        #[clap(short, long, default_value_t = 4)]
+        #[arg(value_parser = clap::value_parser!(u8).range(2..))]
+        wallets: u8,
+
+        /// Number of loops (1-65535)
+        #[clap(short, long, default_value_t = 4)]
+        #[arg(value_parser = clap::value_parser!(u16).range(1..))]
+        loops: u16,
+    },
+
+    /// `loops` times send an asset from a new sender wallet to a common receiver, then reorg out
+    /// the block confirming the transfer, mining a competing chain, and record the resulting
+    /// transfer statuses and balances
+    Reorg {
+        /// Include a conflicting spend of the transfer inputs in the competing chain
+        #[clap(long)]
+        conflict: bool,
+
+        /// Number of blocks of the competing chain (1-4294967295)
+        #[clap(short, long, default_value_t = 2)]
+        #[arg(value_parser = clap::value_parser!(u32).range(1..))]
+        blocks: u32,
+
+        /// Number of loops (1-254)
+        #[clap(short, long, default_value_t = 4)]
+        #[arg(value_parser = clap::value_parser!(u8).range(1..255))]
+        loops: u8,
+    },
+
+    /// Issue `assets` assets, then `loops` times send all of them from the issuer wallet to
+    /// `recipients-per-asset` receiver wallets in a single transaction
+    BatchSend {
+        /// Number of assets (1-5)
+        #[clap(short, long, default_value_t = 1)]
+        #[arg(value_parser = clap::value_parser!(u8).range(1..=5))]
+        assets: u8,
+
+        /// Number of receiver wallets paid in each transaction, alternating blinded and witness
+        /// recipients if `--witness` is set (2-255)
+        #[clap(short, long, default_value_t = 3)]
+        #[arg(value_parser = clap::value_parser!(u8).range(2..))]
+        recipients_per_asset: u8,
+
+        /// Number of loops (1-65535)
+        #[clap(short, long, default_value_t = 4)]
+        #[arg(value_parser = clap::value_parser!(u16).range(1..))]
+        loops: u16,
+    },
+
+    /// Run the scenario described by a TOML or YAML file (see the `scenarios` directory for
+    /// examples)
+    Run {
+        /// Scenario file path (.toml, .yaml or .yml)
+        file: PathBuf,
+    },
+
+    /// Run a scenario for each combination of the given parameter values, reusing the running
+    /// services and writing a single report, keyed by the parameter values (e.g. `sweep -p
+    /// loops=4,8 -p utxo-size=1000..=3000:1000 -- send-loop`)
+    Sweep {
+        /// Parameter to sweep, as <NAME>=<VALUES>, with NAME the long name of a global or scenario
+        /// option and VALUES a comma-separated list of values or <START>..=<END>[:<STEP>] integer
+        /// ranges (can be repeated)
+        #[clap(short, long = "param", required = true)]
+        params: Vec<SweepParam>,
+
+        /// Number of runs of each combination (1-255)
+        #[clap(short, long, default_value_t = 1)]
+        #[arg(value_parser = clap::value_parser!(u8).range(1..))]
+        repeats: u8,
+
+        /// Scenario command line, after `--`
+        #[clap(last = true, required = true)]
+        scenario: Vec<String>,
+    },
+
+    /// Compare a candidate report with a baseline one, aligning transfers by their position in
+    /// the scenario, and fail if the given thresholds are exceeded (no services are started)
+    Compare {
+        /// Baseline report file path
+        baseline: PathBuf,
+
+        /// Candidate report file path
+        candidate: PathBuf,
+
+        /// Maximum allowed increase (percent) of the median time of each step
+        #[clap(short, long, default_value_t = 20)]
+        time_threshold: u16,
+
+        /// Maximum allowed increase (percent) of each consignment size
+        #[clap(short, long, default_value_t = 5)]
+        size_threshold: u16,
+    },
 }
@@ -1,6 +1,125 @@
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpStream, ToSocketAddrs};
 use std::process::{Command, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Condvar, Mutex, OnceLock};
+use std::time::{Duration, Instant};
 
-pub(crate) fn start_services() {
+use serde_json::{json, Value};
+use tracing::info_span;
+
+static BITCOIND: OnceLock<BitcoindRpc> = OnceLock::new();
+static ENDPOINTS: OnceLock<Endpoints> = OnceLock::new();
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+const ELECTRUM_POLL_INTERVAL: Duration = Duration::from_millis(50);
+const ELECTRUM_SYNC_TIMEOUT: Duration = Duration::from_secs(60);
+/// Delay waited for servers that cannot be polled
+const ELECTRUM_SYNC_DELAY: Duration = Duration::from_secs(1);
+
+/// Endpoints of the electrum server and the RGB proxy server
+struct Endpoints {
+    electrum_url: String,
+    transport_endpoint: String,
+}
+
+const MINER_WALLET: &str = "miner";
+
+/// Minimal bitcoind JSON-RPC client
+pub(crate) struct BitcoindRpc {
+    client: reqwest::blocking::Client,
+    url: String,
+    user: String,
+    password: String,
+    miner_address: OnceLock<String>,
+}
+
+impl BitcoindRpc {
+    fn call(&self, wallet: Option<&str>, method: &str, params: Value) -> Result<Value, String> {
+        let _span = info_span!("bitcoind", label = format!("bitcoind {method}")).entered();
+        let url = match wallet {
+            Some(wallet) => format!("{}/wallet/{wallet}", self.url.trim_end_matches('/')),
+            None => self.url.clone(),
+        };
+        let request = json!({
+            "jsonrpc": "1.0",
+            "id": "rgb-lib-stress-test",
+            "method": method,
+            "params": params,
+        });
+        let mut response: Value = self
+            .client
+            .post(url)
+            .basic_auth(&self.user, Some(&self.password))
+            .json(&request)
+            .send()
+            .and_then(|r| r.json())
+            .map_err(|e| format!("bitcoind RPC '{method}' failed: {e}"))?;
+        match response.get("error") {
+            Some(error) if !error.is_null() => {
+                Err(format!("bitcoind RPC '{method}' returned error: {error}"))
+            }
+            _ => Ok(response["result"].take()),
+        }
+    }
+
+    fn miner_address(&self) -> &str {
+        self.miner_address.get_or_init(|| {
+            self.call(Some(MINER_WALLET), "getnewaddress", json!([]))
+                .expect("miner address should have been generated")
+                .as_str()
+                .expect("address should be a string")
+                .to_string()
+        })
+    }
+}
+
+/// Configure the bitcoind RPC client, must be called before any other bitcoind operation
+pub(crate) fn init_bitcoind(url: &str, user: &str, password: &str) {
+    let rpc = BitcoindRpc {
+        client: reqwest::blocking::Client::new(),
+        url: url.to_string(),
+        user: user.to_string(),
+        password: password.to_string(),
+        miner_address: OnceLock::new(),
+    };
+    if BITCOIND.set(rpc).is_err() {
+        panic!("bitcoind RPC client already initialized");
+    }
+}
+
+/// Configure the electrum and RGB proxy endpoints, must be called before setting up wallets
+pub(crate) fn init_endpoints(electrum_url: &str, transport_endpoint: &str) {
+    let endpoints = Endpoints {
+        electrum_url: electrum_url.to_string(),
+        transport_endpoint: transport_endpoint.to_string(),
+    };
+    if ENDPOINTS.set(endpoints).is_err() {
+        panic!("endpoints already initialized");
+    }
+}
+
+fn endpoints() -> &'static Endpoints {
+    ENDPOINTS
+        .get()
+        .expect("endpoints should have been initialized")
+}
+
+pub(crate) fn electrum_url() -> &'static str {
+    &endpoints().electrum_url
+}
+
+pub(crate) fn transport_endpoint() -> &'static str {
+    &endpoints().transport_endpoint
+}
+
+fn bitcoind() -> &'static BitcoindRpc {
+    BITCOIND
+        .get()
+        .expect("bitcoind RPC client should have been initialized")
+}
+
+fn start_services() {
     println!("start services");
     let status = Command::new("./services.sh")
         .stdout(Stdio::null())
@@ -11,56 +130,368 @@ pub(crate) fn start_services() {
     assert!(status.success());
 }
 
-pub(crate) fn stop_services() {
+fn stop_services() -> Result<(), String> {
     println!("\nstop services");
     let status = Command::new("./services.sh")
         .stdout(Stdio::null())
         .stderr(Stdio::null())
         .arg("stop")
         .status()
-        .expect("failed to stop services");
-    assert!(status.success());
+        .map_err(|e| format!("failed to stop services: {e}"))?;
+    if !status.success() {
+        return Err(format!("failed to stop services: {status}"));
+    }
+    Ok(())
 }
 
-pub(crate) fn fund_wallet(address: &str, amount: &str) {
-    let status = Command::new("docker")
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .arg("compose")
-        .args(_bitcoin_cli())
-        .arg("-rpcwallet=miner")
-        .arg("sendtoaddress")
-        .arg(address)
-        .arg(amount)
-        .status()
+/// Stop services after a failure, unless they should be kept for debugging
+fn stop_services_on_failure(keep_on_failure: bool) {
+    if keep_on_failure {
+        println!("\nkeeping services running (stop them with `./services.sh stop`)");
+    } else if let Err(e) = stop_services() {
+        eprintln!("{e}");
+    }
+}
+
+/// Whether an interrupt or termination signal has been received
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Services started by the guard, to be stopped on interrupt: whether to keep them on failure
+/// and whether they have already been stopped
+static SERVICES: Mutex<Option<(bool, Arc<AtomicBool>)>> = Mutex::new(None);
+
+/// Handle interrupt and termination signals
+///
+/// If the report has transfers not yet written to its file, the run goes on until the current
+/// transfer is written, then the report is flushed and the run exits (see `Report::write`),
+/// unless a second signal is received. Otherwise the run exits right away.
+pub(crate) fn handle_interrupts(has_unflushed: fn() -> bool) {
+    ctrlc::set_handler(move || {
+        if !INTERRUPTED.swap(true, Ordering::SeqCst) && has_unflushed() {
+            eprintln!(
+                "\ninterrupted, exiting once the current transfer is written to the report \
+                (interrupt again to exit now)"
+            );
+            return;
+        }
+        exit_interrupted();
+    })
+    .expect("signal handler should have been set");
+}
+
+pub(crate) fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Exit an interrupted run, stopping the services if started by the guard
+pub(crate) fn exit_interrupted() -> ! {
+    eprintln!("\ninterrupted, the report contains the transfers completed so far");
+    if let Some((keep_on_failure, stopped)) = SERVICES.lock().unwrap().as_ref() {
+        if !stopped.swap(true, Ordering::SeqCst) {
+            stop_services_on_failure(*keep_on_failure);
+        }
+    }
+    std::process::exit(130);
+}
+
+/// Guard for the docker services, stopping them when dropped (including on panic) or when the
+/// run is interrupted
+pub(crate) struct ServicesGuard {
+    keep_on_failure: bool,
+    succeeded: bool,
+    stopped: Arc<AtomicBool>,
+}
+
+impl ServicesGuard {
+    pub(crate) fn start(keep_on_failure: bool) -> Self {
+        let stopped = Arc::new(AtomicBool::new(false));
+        *SERVICES.lock().unwrap() = Some((keep_on_failure, stopped.clone()));
+        let guard = ServicesGuard {
+            keep_on_failure,
+            succeeded: false,
+            stopped,
+        };
+        start_services();
+        guard
+    }
+
+    /// Stop services after a successful run
+    pub(crate) fn stop(mut self) {
+        self.succeeded = true;
+    }
+}
+
+impl Drop for ServicesGuard {
+    fn drop(&mut self) {
+        if self.stopped.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        if self.succeeded {
+            // panicking in drop aborts the process if already unwinding, so only log the error
+            if let Err(e) = stop_services() {
+                eprintln!("{e}");
+            }
+        } else {
+            stop_services_on_failure(self.keep_on_failure);
+        }
+    }
+}
+
+/// Check that bitcoind, the electrum server and the RGB proxy server are reachable
+pub(crate) fn check_services() -> Result<(), String> {
+    println!("checking services");
+    let block_count = get_block_count()?;
+    println!("- bitcoind: OK, block count {block_count}");
+    check_electrum(electrum_url())?;
+    println!("- electrum: OK");
+    check_proxy(transport_endpoint())?;
+    println!("- proxy: OK");
+    Ok(())
+}
+
+fn check_electrum(url: &str) -> Result<(), String> {
+    let err = |e: String| format!("electrum server '{url}' not reachable: {e}");
+    match url.split_once("://").unwrap_or(("tcp", url)) {
+        ("tcp", address) => electrum_call(address, "server.ping").map(|_| ()),
+        // only check the connection for non-plaintext servers
+        (_, address) => electrum_connect(address).map(|_| ()),
+    }
+    .map_err(err)
+}
+
+fn electrum_connect(address: &str) -> Result<TcpStream, String> {
+    let socket_addr = address
+        .to_socket_addrs()
+        .map_err(|e| e.to_string())?
+        .next()
+        .ok_or_else(|| "cannot resolve address".to_string())?;
+    TcpStream::connect_timeout(&socket_addr, HEALTH_CHECK_TIMEOUT).map_err(|e| e.to_string())
+}
+
+/// Call a method, without parameters, of a plaintext electrum server
+fn electrum_call(address: &str, method: &str) -> Result<Value, String> {
+    let _span = info_span!("electrum", label = format!("electrum {method}")).entered();
+    let mut stream = electrum_connect(address)?;
+    stream
+        .set_read_timeout(Some(HEALTH_CHECK_TIMEOUT))
+        .map_err(|e| e.to_string())?;
+    let request = json!({"jsonrpc": "2.0", "method": method, "params": [], "id": 0});
+    stream
+        .write_all(format!("{request}\n").as_bytes())
+        .map_err(|e| e.to_string())?;
+    let mut line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .map_err(|e| e.to_string())?;
+    let mut response: Value = serde_json::from_str(&line).map_err(|e| e.to_string())?;
+    match response.get("error") {
+        Some(error) if !error.is_null() => Err(error.to_string()),
+        _ => Ok(response["result"].take()),
+    }
+}
+
+/// Wait for the electrum server to index the bitcoind chain tip
+///
+/// Plaintext servers are polled until their tip header matches the bitcoind one (or is higher,
+/// if more blocks have been mined meanwhile), so that the following wallet refreshes do not
+/// include indexing lag. Other servers cannot be polled, so a fixed delay is waited instead.
+pub(crate) fn wait_electrum_sync() -> Result<(), String> {
+    let _span = info_span!("electrum wait").entered();
+    let url = electrum_url();
+    let (scheme, address) = url.split_once("://").unwrap_or(("tcp", url));
+    if scheme != "tcp" {
+        std::thread::sleep(ELECTRUM_SYNC_DELAY);
+        return Ok(());
+    }
+    let rpc = bitcoind();
+    let hash = get_best_block_hash()?;
+    let height = rpc.call(None, "getblockheader", json!([hash, true]))?["height"]
+        .as_u64()
+        .ok_or("invalid block header".to_string())?;
+    let header = rpc.call(None, "getblockheader", json!([hash, false]))?;
+    let t_start = Instant::now();
+    loop {
+        let tip = electrum_call(address, "blockchain.headers.subscribe")
+            .map_err(|e| format!("electrum server '{url}' tip request failed: {e}"))?;
+        match tip["height"].as_u64() {
+            Some(tip_height) if tip_height > height => return Ok(()),
+            Some(tip_height) if tip_height == height && tip["hex"] == header => return Ok(()),
+            _ => {}
+        }
+        if t_start.elapsed() > ELECTRUM_SYNC_TIMEOUT {
+            return Err(format!(
+                "electrum server '{url}' has not indexed block {hash} after {}s",
+                ELECTRUM_SYNC_TIMEOUT.as_secs()
+            ));
+        }
+        std::thread::sleep(ELECTRUM_POLL_INTERVAL);
+    }
+}
+
+fn check_proxy(transport_endpoint: &str) -> Result<(), String> {
+    let err = |e: String| format!("proxy server '{transport_endpoint}' not reachable: {e}");
+    let url = if let Some(rest) = transport_endpoint.strip_prefix("rpcs://") {
+        format!("https://{rest}")
+    } else if let Some(rest) = transport_endpoint.strip_prefix("rpc://") {
+        format!("http://{rest}")
+    } else {
+        return Err(err("unsupported transport endpoint".to_string()));
+    };
+    let request = json!({"jsonrpc": "2.0", "id": "1", "method": "server.info", "params": null});
+    let response: Value = reqwest::blocking::Client::new()
+        .post(url)
+        .timeout(HEALTH_CHECK_TIMEOUT)
+        .json(&request)
+        .send()
+        .and_then(|r| r.json())
+        .map_err(|e| err(e.to_string()))?;
+    match response.get("error") {
+        Some(error) if !error.is_null() => Err(err(error.to_string())),
+        _ => Ok(()),
+    }
+}
+
+pub(crate) fn fund_wallet(address: &str, amount_sat: u64) {
+    let amount_btc = format!(
+        "{}.{:08}",
+        amount_sat / 100_000_000,
+        amount_sat % 100_000_000
+    );
+    bitcoind()
+        .call(
+            Some(MINER_WALLET),
+            "sendtoaddress",
+            json!([address, amount_btc]),
+        )
         .expect("failed to fund wallet");
-    assert!(status.success());
 }
 
-pub(crate) fn mine() {
-    let status = Command::new("docker")
-        .stdin(Stdio::null())
-        .stdout(Stdio::null())
-        .stderr(Stdio::null())
-        .arg("compose")
-        .args(_bitcoin_cli())
-        .arg("-rpcwallet=miner")
-        .arg("-generate")
-        .arg("1")
-        .status()
-        .expect("failed to mine");
-    assert!(status.success());
+/// State of the mining coordinator, counting mining requests and the ones served
+#[derive(Default)]
+struct MiningState {
+    requested: u64,
+    served: u64,
+    mining: bool,
+}
+
+/// Coordinator sharing mined blocks between concurrent transfers
+#[derive(Default)]
+struct MiningCoordinator {
+    state: Mutex<MiningState>,
+    mined: Condvar,
+}
+
+static MINING: OnceLock<MiningCoordinator> = OnceLock::new();
+
+/// Mine a block including the transactions broadcast before the call
+///
+/// Concurrent callers share blocks: a caller waits for the block being mined if its request came
+/// before mining started, otherwise for the next one, which is mined by a single caller.
+pub(crate) fn mine() -> Result<(), String> {
+    let _span = info_span!("mine").entered();
+    let coordinator = MINING.get_or_init(MiningCoordinator::default);
+    let mut state = coordinator.state.lock().unwrap();
+    state.requested += 1;
+    let ticket = state.requested;
+    while state.served < ticket {
+        if state.mining {
+            state = coordinator.mined.wait(state).unwrap();
+            continue;
+        }
+        state.mining = true;
+        let covered = state.requested;
+        drop(state);
+        let res = mine_blocks(1);
+        state = coordinator.state.lock().unwrap();
+        state.mining = false;
+        if res.is_ok() {
+            state.served = covered;
+        }
+        // on failure a waiting caller takes over mining
+        coordinator.mined.notify_all();
+        res?;
+    }
+    Ok(())
+}
+
+fn mine_blocks(blocks: u32) -> Result<(), String> {
+    let rpc = bitcoind();
+    rpc.call(
+        Some(MINER_WALLET),
+        "generatetoaddress",
+        json!([blocks, rpc.miner_address()]),
+    )?;
+    Ok(())
+}
+
+pub(crate) fn miner_address() -> &'static str {
+    bitcoind().miner_address()
+}
+
+pub(crate) fn get_best_block_hash() -> Result<String, String> {
+    bitcoind()
+        .call(None, "getbestblockhash", json!([]))?
+        .as_str()
+        .map(str::to_string)
+        .ok_or("invalid block hash".to_string())
+}
+
+pub(crate) fn get_block_txids(hash: &str) -> Result<Vec<String>, String> {
+    let mut block = bitcoind().call(None, "getblock", json!([hash]))?;
+    serde_json::from_value(block["tx"].take()).map_err(|e| format!("invalid block: {e}"))
+}
+
+/// Mark a block as invalid, disconnecting it and its descendants from the active chain
+///
+/// Transactions of the disconnected blocks go back to the mempool.
+pub(crate) fn invalidate_block(hash: &str) -> Result<(), String> {
+    bitcoind().call(None, "invalidateblock", json!([hash]))?;
+    Ok(())
+}
+
+/// Finalize a signed PSBT, returning the raw transaction without broadcasting it
+pub(crate) fn finalize_psbt(psbt: &str) -> Result<String, String> {
+    let finalized = bitcoind().call(None, "finalizepsbt", json!([psbt, true]))?;
+    if finalized["complete"] != json!(true) {
+        return Err("PSBT is not complete".to_string());
+    }
+    finalized["hex"]
+        .as_str()
+        .map(str::to_string)
+        .ok_or("missing finalized transaction".to_string())
+}
+
+/// Mine a competing chain of `blocks` blocks, the first one including only the given raw
+/// transaction if one is given
+///
+/// A transaction conflicting with mempool ones evicts them, so they are never confirmed.
+pub(crate) fn mine_competing_chain(blocks: u32, conflict: Option<&str>) -> Result<(), String> {
+    let rpc = bitcoind();
+    let mut blocks = blocks;
+    if let Some(tx) = conflict {
+        rpc.call(
+            Some(MINER_WALLET),
+            "generateblock",
+            json!([rpc.miner_address(), [tx]]),
+        )?;
+        blocks -= 1;
+    }
+    if blocks > 0 {
+        mine_blocks(blocks)?;
+    }
+    Ok(())
+}
+
+pub(crate) fn get_block_count() -> Result<u64, String> {
+    bitcoind()
+        .call(None, "getblockcount", json!([]))?
+        .as_u64()
+        .ok_or("invalid block count".to_string())
 }
 
-fn _bitcoin_cli() -> [String; 7] {
-    [
-        "exec".to_string(),
-        "-T".to_string(),
-        "-u".to_string(),
-        "blits".to_string(),
-        "bitcoind".to_string(),
-        "bitcoin-cli".to_string(),
-        "-regtest".to_string(),
-    ]
+pub(crate) fn get_raw_mempool() -> Vec<String> {
+    let txids = bitcoind()
+        .call(None, "getrawmempool", json!([]))
+        .expect("failed to get mempool");
+    serde_json::from_value(txids).expect("mempool should be a list of txids")
 }
@@ -0,0 +1,709 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{Seek, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::Duration;
+
+use crate::manifest;
+use crate::opts::{OnError, ReportFormat};
+use crate::regtest;
+use crate::soak::Monitor;
+use crate::sweep;
+
+/// Transfer send mode
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum SendMode {
+    Blind,
+    Witness,
+}
+
+impl SendMode {
+    pub(crate) fn from_witness(witness: bool) -> Self {
+        if witness {
+            SendMode::Witness
+        } else {
+            SendMode::Blind
+        }
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            SendMode::Blind => "blind",
+            SendMode::Witness => "witness",
+        }
+    }
+
+    fn from_str(mode: &str) -> Result<Self, String> {
+        match mode {
+            "blind" => Ok(SendMode::Blind),
+            "witness" => Ok(SendMode::Witness),
+            _ => Err(format!("invalid send mode '{mode}'")),
+        }
+    }
+}
+
+/// Durations, in milliseconds, of the steps of a transfer
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[serde(default)]
+pub(crate) struct StepTimes {
+    pub(crate) send: u64,
+    pub(crate) recv_refresh_1: u64,
+    pub(crate) send_refresh_1: u64,
+    pub(crate) mining: u64,
+    /// Wait for the electrum server to index the mined block
+    pub(crate) electrum_wait: u64,
+    pub(crate) recv_refresh_2: u64,
+    pub(crate) send_refresh_2: u64,
+    pub(crate) total: u64,
+}
+
+/// Info on an asset sent in a transfer
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct AssetResult {
+    pub(crate) ticker: String,
+    /// Asset schema ("nia", "cfa" or "uda")
+    #[serde(default)]
+    pub(crate) schema: String,
+    pub(crate) consignment_size: u64,
+    pub(crate) recipient_id: String,
+}
+
+/// Failure of a transfer, with the step that failed and the rgb-lib error
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct TransferFailure {
+    pub(crate) step: String,
+    pub(crate) error: String,
+    pub(crate) message: String,
+}
+
+impl TransferFailure {
+    pub(crate) fn new(step: &str, error: &str, message: String) -> Self {
+        TransferFailure {
+            step: step.to_string(),
+            error: error.to_string(),
+            message,
+        }
+    }
+}
+
+/// RGB balance of an asset
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub(crate) struct BalanceResult {
+    pub(crate) settled: u64,
+    pub(crate) future: u64,
+    pub(crate) spendable: u64,
+}
+
+impl BalanceResult {
+    fn to_csv(&self) -> String {
+        format!("{}/{}/{}", self.settled, self.future, self.spendable)
+    }
+
+    fn from_csv(balance: &str) -> Result<Self, String> {
+        let values = balance
+            .split('/')
+            .map(|v| v.parse::<u64>())
+            .collect::<Result<Vec<u64>, _>>()
+            .map_err(|e| format!("invalid balance '{balance}': {e}"))?;
+        match values[..] {
+            [settled, future, spendable] => Ok(BalanceResult {
+                settled,
+                future,
+                spendable,
+            }),
+            _ => Err(format!("invalid balance '{balance}'")),
+        }
+    }
+}
+
+/// State of the sender and receiver after the block confirming a transfer has been reorged out
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct ReorgResult {
+    /// Whether the competing chain included a conflicting spend of the transfer inputs
+    pub(crate) conflict: bool,
+    pub(crate) sender_status: String,
+    pub(crate) receiver_status: String,
+    pub(crate) sender_balance: BalanceResult,
+    pub(crate) receiver_balance: BalanceResult,
+}
+
+/// Backup of a wallet taken after a transfer
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct BackupResult {
+    /// Fingerprint of the backed up wallet
+    pub(crate) wallet: String,
+    /// Number of succeeded transfers the wallet took part in
+    pub(crate) transfers: u32,
+    /// Backup file size, in bytes
+    pub(crate) size: u64,
+    /// Backup time, in milliseconds
+    pub(crate) time: u64,
+}
+
+/// Result of a transfer
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub(crate) struct TransferResult {
+    /// Parameter values of the sweep run the transfer belongs to
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub(crate) params: BTreeMap<String, String>,
+    pub(crate) sender: String,
+    pub(crate) receiver: String,
+    pub(crate) mode: SendMode,
+    /// Whether the transfer was a donation, which skips the ACK refreshes
+    #[serde(default)]
+    pub(crate) donation: bool,
+    pub(crate) times: StepTimes,
+    pub(crate) txid: String,
+    pub(crate) assets: Vec<AssetResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) failure: Option<TransferFailure>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) reorg: Option<ReorgResult>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub(crate) backups: Vec<BackupResult>,
+    /// Whether the transfer was completed before a resumed run was interrupted, so it is already
+    /// in the report
+    #[serde(skip)]
+    pub(crate) replayed: bool,
+}
+
+impl TransferResult {
+    pub(crate) fn succeeded(&self) -> bool {
+        self.failure.is_none()
+    }
+
+    fn to_csv(&self) -> String {
+        let t = &self.times;
+        let assets = self
+            .assets
+            .iter()
+            .map(|a| {
+                format!(
+                    ",{},{},{},{}",
+                    a.ticker, a.schema, a.consignment_size, a.recipient_id
+                )
+            })
+            .collect::<String>();
+        let (result, step, error, message) = match &self.failure {
+            Some(f) => (
+                "failed",
+                f.step.as_str(),
+                f.error.as_str(),
+                csv_text(&f.message),
+            ),
+            None => ("ok", "", "", String::new()),
+        };
+        let reorg = match &self.reorg {
+            Some(r) => format!(
+                "{},{},{},{},{}",
+                if r.conflict { "conflict" } else { "reorg" },
+                r.sender_status,
+                r.receiver_status,
+                r.sender_balance.to_csv(),
+                r.receiver_balance.to_csv()
+            ),
+            None => ",,,,".to_string(),
+        };
+        let backup = |wallet: &str| match self.backups.iter().find(|b| b.wallet == wallet) {
+            Some(b) => format!("{},{}", b.size, b.time),
+            None => ",".to_string(),
+        };
+        format!(
+            "{},\"{}\",\"{}\",{},{},{},{},{},{},{},{},{},{},{},{},{},{},\"{}\",{},{},{}{}\n",
+            sweep::format_params(&self.params),
+            self.sender,
+            self.receiver,
+            self.mode.as_str(),
+            self.donation,
+            t.send,
+            t.recv_refresh_1,
+            t.send_refresh_1,
+            t.mining,
+            t.electrum_wait,
+            t.recv_refresh_2,
+            t.send_refresh_2,
+            t.total,
+            self.txid,
+            result,
+            step,
+            error,
+            message,
+            reorg,
+            backup(&self.sender),
+            backup(&self.receiver),
+            assets,
+        )
+    }
+}
+
+/// Make free text safe to be written as a single CSV field
+fn csv_text(text: &str) -> String {
+    text.replace([',', '\n', '\r'], " ").replace('"', "'")
+}
+
+#[derive(Serialize)]
+struct JsonReport<'a> {
+    seed: u64,
+    transfers: &'a [TransferResult],
+}
+
+#[derive(Deserialize)]
+struct JsonReportOwned {
+    transfers: Vec<TransferResult>,
+}
+
+#[derive(Serialize)]
+struct NdjsonHeader {
+    seed: u64,
+}
+
+/// Report file, written in the selected format as transfers complete
+pub(crate) struct Report {
+    file: fs::File,
+    format: ReportFormat,
+    seed: u64,
+    abort_on_failure: bool,
+    transfers: Vec<TransferResult>,
+    /// Parameter values of the current sweep run, added to the written transfers
+    params: BTreeMap<String, String>,
+    /// Whether the current transfers belong to a warm-up repetition
+    warmup: bool,
+    /// Rolling statistics of a soak run, which also limit JSON rewrites to once per interval
+    monitor: Option<Monitor>,
+}
+
+impl Report {
+    pub(crate) fn new(path: &Path, format: ReportFormat, seed: u64, on_error: OnError) -> Self {
+        let file = fs::File::create(path).expect("file should have been created");
+        let mut report = Report {
+            file,
+            format,
+            seed,
+            abort_on_failure: on_error == OnError::Abort,
+            transfers: vec![],
+            params: BTreeMap::new(),
+            warmup: false,
+            monitor: None,
+        };
+        match format {
+            ReportFormat::Csv => {
+                report.write_str(&format!("# seed: {seed}\n"));
+                report.write_str(concat!(
+                    "params",
+                    ",sender",
+                    ",receiver",
+                    ",send mode",
+                    ",donation",
+                    ",send,recv refresh 1,send refresh 1,mining,electrum wait",
+                    ",recv refresh 2,send refresh 2",
+                    ",total time",
+                    ",txid",
+                    ",result,failed step,error,error message",
+                    ",reorg,sender status,receiver status,sender balance,receiver balance",
+                    ",sender backup size,sender backup time",
+                    ",receiver backup size,receiver backup time",
+                    ",ticker,schema,consignment size,recipient id\n",
+                ));
+            }
+            ReportFormat::Json => report.rewrite_json(),
+            ReportFormat::Ndjson => {
+                let header = serde_json::to_string(&NdjsonHeader { seed }).unwrap();
+                report.write_str(&format!("{header}\n"));
+            }
+        }
+        report
+    }
+
+    /// Reopen the report of an interrupted run, appending new transfers to the existing ones
+    ///
+    /// Transfers not recorded as completed in the run manifest, written by a group the run was
+    /// interrupted in the middle of, are dropped, as the resumed run carries them out again.
+    pub(crate) fn resume(
+        path: &Path,
+        format: ReportFormat,
+        seed: u64,
+        on_error: OnError,
+    ) -> Result<Self, String> {
+        let mut transfers = load(path)?;
+        let completed = manifest::completed_transfers().unwrap_or_default() as usize;
+        let dropped = transfers.len().saturating_sub(completed);
+        transfers.truncate(completed);
+        let file = fs::OpenOptions::new()
+            .append(format != ReportFormat::Json)
+            .write(true)
+            .open(path)
+            .map_err(|e| format!("cannot open report file '{}': {e}", path.display()))?;
+        let mut report = Report {
+            file,
+            format,
+            seed,
+            abort_on_failure: on_error == OnError::Abort,
+            transfers,
+            params: BTreeMap::new(),
+            warmup: false,
+            monitor: None,
+        };
+        if dropped > 0 {
+            println!("dropping {dropped} transfer(s) of an interrupted group from the report");
+            report.truncate(path)?;
+        }
+        Ok(report)
+    }
+
+    /// Rewrite the report file with the transfers kept in the report
+    fn truncate(&mut self, path: &Path) -> Result<(), String> {
+        let header_lines = match self.format {
+            ReportFormat::Json => {
+                self.rewrite_json();
+                return Ok(());
+            }
+            // seed comment and column names
+            ReportFormat::Csv => 2,
+            ReportFormat::Ndjson => 1,
+        };
+        let content = fs::read_to_string(path)
+            .map_err(|e| format!("cannot read report file '{}': {e}", path.display()))?;
+        let kept: String = content
+            .split_inclusive('\n')
+            .take(header_lines + self.transfers.len())
+            .collect();
+        self.file
+            .set_len(0)
+            .expect("file should have been truncated");
+        self.write_str(&kept);
+        Ok(())
+    }
+
+    /// Set the parameter values of the sweep run the next transfers belong to
+    pub(crate) fn set_params(&mut self, params: BTreeMap<String, String>) {
+        self.params = params;
+    }
+
+    /// Print rolling statistics of the written transfers every given interval
+    ///
+    /// A JSON report is then rewritten once per interval instead of after each transfer, as
+    /// rewriting it gets slow when a long run accumulates many transfers.
+    pub(crate) fn monitor(&mut self, interval: Duration) {
+        self.monitor = Some(Monitor::new(interval));
+    }
+
+    /// Set whether the next transfers belong to a warm-up repetition, which are not reported
+    pub(crate) fn set_warmup(&mut self, warmup: bool) {
+        self.warmup = warmup;
+    }
+
+    /// Add a transfer to the report, recording it as completed in the run manifest
+    pub(crate) fn write(&mut self, result: TransferResult) {
+        self.write_group(vec![result]);
+    }
+
+    /// Add transfers carried out together (e.g. a batch) to the report, recording them as
+    /// completed in the run manifest at once
+    ///
+    /// Transfers replayed by a resumed run are already in the report, so they are skipped, as
+    /// are transfers of warm-up repetitions. If a transfer failed and the run should abort on
+    /// errors, this panics once the transfers have been recorded. If the run has been
+    /// interrupted, it exits once the report has been flushed.
+    pub(crate) fn write_group(&mut self, results: Vec<TransferResult>) {
+        let first = self.transfers.len();
+        for result in results {
+            self.record(result);
+        }
+        let recorded = &self.transfers[first..];
+        manifest::record_transfers(recorded);
+        if self.abort_on_failure {
+            if let Some(failure) = recorded.iter().find_map(|t| t.failure.as_ref()) {
+                abort_run(failure);
+            }
+        }
+        if regtest::interrupted() {
+            self.flush();
+            regtest::exit_interrupted();
+        }
+    }
+
+    fn record(&mut self, mut result: TransferResult) {
+        if result.replayed {
+            return;
+        }
+        if self.warmup {
+            if self.abort_on_failure {
+                if let Some(failure) = &result.failure {
+                    abort_run(failure);
+                }
+            }
+            return;
+        }
+        result.params = self.params.clone();
+        match self.format {
+            ReportFormat::Csv => self.write_str(&result.to_csv()),
+            ReportFormat::Json => {}
+            ReportFormat::Ndjson => {
+                let line = serde_json::to_string(&result).unwrap();
+                self.write_str(&format!("{line}\n"));
+            }
+        }
+        let stats = self.monitor.as_mut().and_then(|m| m.record(&result));
+        let abort = self.abort_on_failure && !result.succeeded();
+        self.transfers.push(result);
+        if self.format == ReportFormat::Json {
+            if self.monitor.is_none() || stats.is_some() || abort {
+                self.rewrite_json();
+            } else {
+                UNFLUSHED.store(true, Ordering::SeqCst);
+            }
+        }
+        if let Some(stats) = stats {
+            println!("\n{stats}");
+        }
+    }
+
+    /// Write any transfers not yet in the report file
+    pub(crate) fn flush(&mut self) {
+        if self.format == ReportFormat::Json && self.monitor.is_some() {
+            self.rewrite_json();
+        }
+    }
+
+    pub(crate) fn transfers(&self) -> &[TransferResult] {
+        &self.transfers
+    }
+
+    /// Rewrite the whole JSON document, so the file is always valid
+    fn rewrite_json(&mut self) {
+        UNFLUSHED.store(false, Ordering::SeqCst);
+        let json = serde_json::to_string_pretty(&JsonReport {
+            seed: self.seed,
+            transfers: &self.transfers,
+        })
+        .unwrap();
+        self.file
+            .set_len(0)
+            .expect("file should have been truncated");
+        self.file.rewind().expect("file should have been rewound");
+        self.write_str(&json);
+    }
+
+    fn write_str(&mut self, line: &str) {
+        self.file
+            .write_all(line.as_bytes())
+            .expect("line should have been written");
+        self.file.flush().expect("report should have been flushed");
+    }
+}
+
+/// Whether the report has transfers not yet written to its file
+static UNFLUSHED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the report has transfers not yet written to its file, which happens for JSON reports
+/// of soak runs between stats intervals
+pub(crate) fn has_unflushed() -> bool {
+    UNFLUSHED.load(Ordering::SeqCst)
+}
+
+fn abort_run(failure: &TransferFailure) -> ! {
+    panic!(
+        "transfer failed at {}: {} ({})",
+        failure.step, failure.error, failure.message
+    );
+}
+
+/// Load the transfers of a report file, in any of the supported formats
+pub(crate) fn load(path: &Path) -> Result<Vec<TransferResult>, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("cannot read report file '{}': {e}", path.display()))?;
+    let transfers = if content.trim_start().starts_with('{') {
+        load_json(&content)
+    } else {
+        load_csv(&content)
+    };
+    transfers.map_err(|e| format!("invalid report file '{}': {e}", path.display()))
+}
+
+fn load_json(content: &str) -> Result<Vec<TransferResult>, String> {
+    if let Ok(report) = serde_json::from_str::<JsonReportOwned>(content) {
+        return Ok(report.transfers);
+    }
+    // NDJSON, skipping lines that are not transfers (e.g. the header)
+    let mut transfers = vec![];
+    for (i, line) in content.lines().enumerate() {
+        let value: serde_json::Value =
+            serde_json::from_str(line).map_err(|e| format!("line {}: {e}", i + 1))?;
+        if value.get("sender").is_some() {
+            transfers
+                .push(serde_json::from_value(value).map_err(|e| format!("line {}: {e}", i + 1))?);
+        }
+    }
+    Ok(transfers)
+}
+
+fn load_csv(content: &str) -> Result<Vec<TransferResult>, String> {
+    let mut lines = content
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.starts_with('#') && !l.trim().is_empty());
+    let (_, header) = lines.next().ok_or("missing CSV header")?;
+    let columns: Vec<&str> = header.split(',').collect();
+    let column = |name: &str| columns.iter().position(|c| *c == name);
+    let required = |name: &str| column(name).ok_or(format!("missing CSV column '{name}'"));
+    let (sender, receiver, mode, txid) = (
+        required("sender")?,
+        required("receiver")?,
+        required("send mode")?,
+        required("txid")?,
+    );
+    let time_columns = [
+        required("send")?,
+        required("recv refresh 1")?,
+        required("send refresh 1")?,
+        required("recv refresh 2")?,
+        required("send refresh 2")?,
+        required("total time")?,
+    ];
+    let mining = column("mining");
+    let electrum_wait = column("electrum wait");
+    let params = column("params");
+    let donation = column("donation");
+    let failure_columns = match column("result") {
+        Some(result) => Some((
+            result,
+            required("failed step")?,
+            required("error")?,
+            required("error message")?,
+        )),
+        None => None,
+    };
+    let reorg_columns = match column("reorg") {
+        Some(reorg) => Some((
+            reorg,
+            required("sender status")?,
+            required("receiver status")?,
+            required("sender balance")?,
+            required("receiver balance")?,
+        )),
+        None => None,
+    };
+    let backup_columns = match column("sender backup size") {
+        Some(sender_size) => Some([
+            (sender_size, required("sender backup time")?),
+            (
+                required("receiver backup size")?,
+                required("receiver backup time")?,
+            ),
+        ]),
+        None => None,
+    };
+    let first_asset = required("ticker")?;
+    // reports written before the schema column was added have 3 columns per asset
+    let asset_columns = if column("schema").is_some() { 4 } else { 3 };
+
+    let mut transfers = vec![];
+    for (i, line) in lines {
+        let err = |e: String| format!("line {}: {e}", i + 1);
+        let fields: Vec<&str> = line.split(',').map(|f| f.trim_matches('"')).collect();
+        if fields.len() < first_asset || !(fields.len() - first_asset).is_multiple_of(asset_columns)
+        {
+            return Err(err(format!(
+                "unexpected number of fields ({})",
+                fields.len()
+            )));
+        }
+        let num = |idx: usize| {
+            fields[idx]
+                .parse::<u64>()
+                .map_err(|e| err(format!("invalid number '{}': {e}", fields[idx])))
+        };
+        let assets = fields[first_asset..]
+            .chunks(asset_columns)
+            .map(|chunk| {
+                let (schema, rest) = if asset_columns == 4 {
+                    (chunk[1], &chunk[2..])
+                } else {
+                    ("", &chunk[1..])
+                };
+                Ok(AssetResult {
+                    ticker: chunk[0].to_string(),
+                    schema: schema.to_string(),
+                    consignment_size: rest[0]
+                        .parse()
+                        .map_err(|e| err(format!("invalid size '{}': {e}", rest[0])))?,
+                    recipient_id: rest[1].to_string(),
+                })
+            })
+            .collect::<Result<Vec<AssetResult>, String>>()?;
+        let reorg = match reorg_columns {
+            Some((reorg, sender_status, receiver_status, sender_balance, receiver_balance))
+                if !fields[reorg].is_empty() =>
+            {
+                Some(ReorgResult {
+                    conflict: fields[reorg] == "conflict",
+                    sender_status: fields[sender_status].to_string(),
+                    receiver_status: fields[receiver_status].to_string(),
+                    sender_balance: BalanceResult::from_csv(fields[sender_balance]).map_err(err)?,
+                    receiver_balance: BalanceResult::from_csv(fields[receiver_balance])
+                        .map_err(err)?,
+                })
+            }
+            _ => None,
+        };
+        let mut backups = vec![];
+        for (wallet, (size, time)) in [sender, receiver]
+            .into_iter()
+            .zip(backup_columns.into_iter().flatten())
+        {
+            if !fields[size].is_empty() {
+                backups.push(BackupResult {
+                    wallet: fields[wallet].to_string(),
+                    // not written to CSV reports
+                    transfers: 0,
+                    size: num(size)?,
+                    time: num(time)?,
+                });
+            }
+        }
+        let params = match params {
+            Some(idx) => fields[idx]
+                .split_whitespace()
+                .map(|p| {
+                    p.split_once('=')
+                        .map(|(name, value)| (name.to_string(), value.to_string()))
+                        .ok_or_else(|| err(format!("invalid parameter '{p}'")))
+                })
+                .collect::<Result<BTreeMap<String, String>, String>>()?,
+            None => BTreeMap::new(),
+        };
+        transfers.push(TransferResult {
+            params,
+            sender: fields[sender].to_string(),
+            receiver: fields[receiver].to_string(),
+            mode: SendMode::from_str(fields[mode]).map_err(err)?,
+            donation: donation.is_some_and(|idx| fields[idx] == "true"),
+            times: StepTimes {
+                send: num(time_columns[0])?,
+                recv_refresh_1: num(time_columns[1])?,
+                send_refresh_1: num(time_columns[2])?,
+                mining: mining.map(num).transpose()?.unwrap_or_default(),
+                electrum_wait: electrum_wait.map(num).transpose()?.unwrap_or_default(),
+                recv_refresh_2: num(time_columns[3])?,
+                send_refresh_2: num(time_columns[4])?,
+                total: num(time_columns[5])?,
+            },
+            txid: fields[txid].to_string(),
+            assets,
+            failure: failure_columns
+                .filter(|(result, ..)| fields[*result] == "failed")
+                .map(|(_, step, error, message)| TransferFailure {
+                    step: fields[step].to_string(),
+                    error: fields[error].to_string(),
+                    message: fields[message].to_string(),
+                }),
+            reorg,
+            backups,
+            replayed: false,
+        });
+    }
+    Ok(transfers)
+}
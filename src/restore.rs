@@ -0,0 +1,358 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use rgb_lib::wallet::{Wallet, WalletData};
+use rgb_lib::{restore_backup, restore_keys, BitcoinNetwork};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+use crate::constants::BACKUP_PASSWORD;
+use crate::regtest;
+use crate::scenarios::WalletInfo;
+
+/// On-chain and RGB state of a wallet
+struct WalletState {
+    /// Outpoints and amounts of the wallet UTXOs
+    utxos: BTreeSet<(String, u64)>,
+    /// Number of RGB allocations on the wallet UTXOs
+    allocations: usize,
+    /// Settled balance of each asset, by asset ID
+    balances: BTreeMap<String, u64>,
+}
+
+/// Outcome of restoring a wallet from its mnemonic
+struct RestoreOutcome {
+    /// Time to create the wallet, go online and sync, in milliseconds
+    time: u64,
+    original: WalletState,
+    restored: WalletState,
+}
+
+impl RestoreOutcome {
+    fn recovered_assets(&self) -> usize {
+        self.original
+            .balances
+            .iter()
+            .filter(|(id, balance)| self.restored.balances.get(*id) == Some(balance))
+            .count()
+    }
+}
+
+/// Open a wallet, go online and sync it, returning its state
+fn open_wallet(wallet_data: WalletData) -> Result<WalletState, String> {
+    let mut wallet = Wallet::new(wallet_data).map_err(|e| e.to_string())?;
+    let online = wallet
+        .go_online(false, regtest::electrum_url().to_string())
+        .map_err(|e| e.to_string())?;
+    let unspents = wallet
+        .list_unspents(Some(online), false)
+        .map_err(|e| e.to_string())?;
+    let assets = wallet.list_assets(vec![]).map_err(|e| e.to_string())?;
+    let nia = assets.nia.unwrap_or_default();
+    let cfa = assets.cfa.unwrap_or_default();
+    let uda = assets.uda.unwrap_or_default();
+    let balances = nia
+        .into_iter()
+        .map(|a| (a.asset_id, a.balance.settled))
+        .chain(cfa.into_iter().map(|a| (a.asset_id, a.balance.settled)))
+        .chain(uda.into_iter().map(|a| (a.asset_id, a.balance.settled)))
+        .collect();
+    Ok(WalletState {
+        allocations: unspents.iter().map(|u| u.rgb_allocations.len()).sum(),
+        utxos: unspents
+            .into_iter()
+            .map(|u| (u.utxo.outpoint.to_string(), u.utxo.btc_amount))
+            .collect(),
+        balances,
+    })
+}
+
+/// Recreate a wallet from its mnemonic in the given data dir and compare it with the original
+fn restore_wallet(info: &WalletInfo, restore_dir: &str) -> Result<RestoreOutcome, String> {
+    let original = open_wallet(info.wallet_data.clone())?;
+
+    let t_begin = Instant::now();
+    let mnemonic = info
+        .wallet_data
+        .mnemonic
+        .clone()
+        .ok_or("wallet has no mnemonic")?;
+    let keys = restore_keys(BitcoinNetwork::Regtest, mnemonic).map_err(|e| e.to_string())?;
+    if keys.xpub_fingerprint != info.fingerprint {
+        return Err(format!(
+            "restored fingerprint {} doesn't match",
+            keys.xpub_fingerprint
+        ));
+    }
+    let restored = open_wallet(WalletData {
+        data_dir: restore_dir.to_string(),
+        pubkey: keys.xpub,
+        mnemonic: Some(keys.mnemonic),
+        ..info.wallet_data.clone()
+    })?;
+    Ok(RestoreOutcome {
+        time: t_begin.elapsed().as_millis() as u64,
+        original,
+        restored,
+    })
+}
+
+/// Create a fresh directory, inside the data dir, to restore wallets into
+fn fresh_dir(data_dir: &str, prefix: &str) -> String {
+    let timestamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let dir = Path::new(data_dir).join(format!("{prefix}-{timestamp}"));
+    std::fs::create_dir_all(&dir).expect("restore dir should have been created");
+    dir.to_str().unwrap().to_string()
+}
+
+/// Balance of an asset, as recorded when a backup is taken
+#[derive(Serialize, Deserialize, PartialEq)]
+struct AssetBalance {
+    settled: u64,
+    future: u64,
+    spendable: u64,
+}
+
+/// Transfer of an asset, with the fields a restored backup is expected to preserve
+#[derive(Serialize, Deserialize, PartialEq)]
+struct TransferState {
+    idx: i32,
+    status: String,
+    amount: u64,
+    kind: String,
+}
+
+/// Balance of each asset of a wallet and the transfers of each of them, by asset ID
+///
+/// Only fields independent of the wallet location are kept (e.g. not media file paths), so that
+/// a wallet restored elsewhere can be compared.
+type BackupState = (
+    BTreeMap<String, AssetBalance>,
+    BTreeMap<String, Vec<TransferState>>,
+);
+
+/// State of a wallet to be compared with the one of its restored backup
+fn assets_and_transfers(wallet: &Wallet) -> Result<BackupState, String> {
+    let assets = wallet.list_assets(vec![]).map_err(|e| e.to_string())?;
+    let balances: BTreeMap<String, AssetBalance> = assets
+        .nia
+        .into_iter()
+        .flatten()
+        .map(|a| (a.asset_id, a.balance))
+        .chain(
+            assets
+                .cfa
+                .into_iter()
+                .flatten()
+                .map(|a| (a.asset_id, a.balance)),
+        )
+        .chain(
+            assets
+                .uda
+                .into_iter()
+                .flatten()
+                .map(|a| (a.asset_id, a.balance)),
+        )
+        .map(|(id, b)| {
+            let balance = AssetBalance {
+                settled: b.settled,
+                future: b.future,
+                spendable: b.spendable,
+            };
+            (id, balance)
+        })
+        .collect();
+    let mut transfers = BTreeMap::new();
+    for asset_id in balances.keys() {
+        let asset_transfers = wallet
+            .list_transfers(Some(asset_id.clone()))
+            .map_err(|e| e.to_string())?
+            .into_iter()
+            .map(|t| TransferState {
+                idx: t.idx,
+                status: format!("{:?}", t.status),
+                amount: t.amount,
+                kind: format!("{:?}", t.kind),
+            })
+            .collect();
+        transfers.insert(asset_id.clone(), asset_transfers);
+    }
+    Ok((balances, transfers))
+}
+
+/// Record the assets and transfers of a wallet next to a backup just taken, as
+/// `<backup>.assets` and `<backup>.transfers`, so the restored backup can be checked against them
+pub(crate) fn record_backup_state(wallet: &Wallet, backup_path: &Path) {
+    let (assets, transfers) =
+        assets_and_transfers(wallet).expect("wallet state should have been read");
+    std::fs::write(
+        backup_path.with_extension("assets"),
+        serde_json::to_string_pretty(&assets).unwrap(),
+    )
+    .expect("backup assets should have been written");
+    std::fs::write(
+        backup_path.with_extension("transfers"),
+        serde_json::to_string_pretty(&transfers).unwrap(),
+    )
+    .expect("backup transfers should have been written");
+}
+
+/// Read the assets or transfers recorded next to a backup
+fn read_backup_state<T: DeserializeOwned>(backup_path: &Path, ext: &str) -> Result<T, String> {
+    let content = std::fs::read_to_string(backup_path.with_extension(ext))
+        .map_err(|e| format!("cannot read recorded {ext}: {e}"))?;
+    serde_json::from_str(&content).map_err(|e| format!("invalid recorded {ext}: {e}"))
+}
+
+/// Latest periodic backup in a wallet backup dir, with the number of transfers it was taken at
+fn latest_backup(backup_dir: &Path) -> Result<(u32, PathBuf), String> {
+    let entries = std::fs::read_dir(backup_dir).map_err(|e| e.to_string())?;
+    entries
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|ext| ext == "backup"))
+        .filter_map(|p| Some((p.file_stem()?.to_str()?.parse::<u32>().ok()?, p)))
+        .max_by_key(|(transfers, _)| *transfers)
+        .ok_or_else(|| "no backup found".to_string())
+}
+
+/// Restore the latest periodic backup of a wallet in the given data dir and compare the assets
+/// and transfers of the restored wallet with the ones recorded when the backup was taken
+///
+/// Returns the number of transfers the backup was taken at, its size and whether assets and
+/// transfers match.
+fn verify_backup(
+    info: &WalletInfo,
+    backup_dir: &Path,
+    restore_dir: &str,
+) -> Result<(u32, u64, bool, bool), String> {
+    let (transfers, backup_path) = latest_backup(backup_dir)?;
+    let size = std::fs::metadata(&backup_path)
+        .map_err(|e| e.to_string())?
+        .len();
+    let assets: BTreeMap<String, AssetBalance> = read_backup_state(&backup_path, "assets")?;
+    let transfer_list: BTreeMap<String, Vec<TransferState>> =
+        read_backup_state(&backup_path, "transfers")?;
+
+    restore_backup(backup_path.to_str().unwrap(), BACKUP_PASSWORD, restore_dir)
+        .map_err(|e| e.to_string())?;
+    let restored = Wallet::new(WalletData {
+        data_dir: restore_dir.to_string(),
+        ..info.wallet_data.clone()
+    })
+    .map_err(|e| e.to_string())?;
+    let (restored_assets, restored_transfers) = assets_and_transfers(&restored)?;
+    Ok((
+        transfers,
+        size,
+        assets == restored_assets,
+        transfer_list == restored_transfers,
+    ))
+}
+
+/// Verify the backups of the wallets backed up during the run
+///
+/// The latest periodic backup of each of them is restored in a fresh data dir, without going
+/// online, and the restored assets and transfers are compared with the ones the wallet had when
+/// the backup was taken.
+pub(crate) fn verify_backups(data_dir: &str, wallets: &[WalletInfo]) -> String {
+    let backups_dir = Path::new(data_dir).join("backups");
+    let backed_up: Vec<&WalletInfo> = wallets
+        .iter()
+        .filter(|w| backups_dir.join(&w.fingerprint).is_dir())
+        .collect();
+    let restore_dir = fresh_dir(data_dir, "backup-restore");
+    println!("\nverify backups of {} wallet(s)", backed_up.len());
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "backup restore (in {restore_dir})\n{:>6} {:16} {:>9} {:>12} {:>8} {:>10}",
+        "wallet", "fingerprint", "backup", "size (bytes)", "assets", "transfers"
+    )
+    .unwrap();
+    let match_str = |matches: bool| if matches { "ok" } else { "diff" };
+    for info in backed_up {
+        match verify_backup(info, &backups_dir.join(&info.fingerprint), &restore_dir) {
+            Ok((backup, size, assets, transfers)) => writeln!(
+                out,
+                "{:>6} {:16} {backup:>9} {size:>12} {:>8} {:>10}",
+                info.wallet_index,
+                info.fingerprint,
+                match_str(assets),
+                match_str(transfers)
+            ),
+            Err(e) => writeln!(
+                out,
+                "{:>6} {:16} backup restore failed: {e}",
+                info.wallet_index, info.fingerprint
+            ),
+        }
+        .unwrap();
+    }
+    out
+}
+
+/// Restore each wallet of the run from its mnemonic, without a backup, in a fresh data dir
+///
+/// Returns a report comparing restored and original wallets: on-chain funds are expected to be
+/// recovered, while RGB allocations are only known to the original wallet.
+pub(crate) fn restore_wallets(data_dir: &str, wallets: &[WalletInfo]) -> String {
+    let restore_dir = fresh_dir(data_dir, "restore");
+    let restore_dir = restore_dir.as_str();
+    println!("\nrestore {} wallet(s) from mnemonic", wallets.len());
+
+    let mut out = String::new();
+    writeln!(
+        out,
+        "wallet restore from mnemonic (in {restore_dir})\n{:>6} {:16} {:>10} {:>9} {:>4} {:>11} {:>8}",
+        "wallet", "fingerprint", "time (ms)", "utxos", "btc", "allocations", "assets"
+    )
+    .unwrap();
+    let (mut lost_allocations, mut lost_assets) = (0, 0);
+    for info in wallets {
+        match restore_wallet(info, restore_dir) {
+            Ok(outcome) => {
+                let (original, restored) = (&outcome.original, &outcome.restored);
+                let recovered = outcome.recovered_assets();
+                lost_allocations += original.allocations.saturating_sub(restored.allocations);
+                lost_assets += original.balances.len() - recovered;
+                writeln!(
+                    out,
+                    "{:>6} {:16} {:>10} {:>9} {:>4} {:>11} {:>8}",
+                    info.wallet_index,
+                    info.fingerprint,
+                    outcome.time,
+                    format!("{}/{}", restored.utxos.len(), original.utxos.len()),
+                    if original.utxos == restored.utxos {
+                        "ok"
+                    } else {
+                        "diff"
+                    },
+                    format!("{}/{}", restored.allocations, original.allocations),
+                    format!("{recovered}/{}", original.balances.len()),
+                )
+                .unwrap();
+            }
+            Err(e) => {
+                writeln!(
+                    out,
+                    "{:>6} {:16} restore failed: {e}",
+                    info.wallet_index, info.fingerprint
+                )
+                .unwrap();
+            }
+        }
+    }
+    writeln!(
+        out,
+        "not recoverable without a backup: {lost_allocations} RGB allocation(s), \
+        {lost_assets} asset balance(s)"
+    )
+    .unwrap();
+    out
+}
@@ -1,29 +1,51 @@
 use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::fmt::Debug;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::str::FromStr;
-use std::time::Instant;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
-use rgb_lib::wallet::{AssetNIA, Assets, Online, ReceiveData, Recipient, RecipientData, Wallet};
-use rgb_lib::{Error, ScriptBuf, SecretSeal, TransferStatus};
+use rgb_lib::wallet::{Assets, Online, ReceiveData, Recipient, RecipientData, Wallet};
+use rgb_lib::{AssetSchema, Error, ScriptBuf, SecretSeal, TransferStatus};
+use tracing::info_span;
 
-use crate::constants::{FEE_RATE, MIN_CONFIRMATIONS, TRANSPORT_ENDPOINT, WITNESS_SATS};
+use crate::constants::{BACKUP_PASSWORD, FEE_RATE, RETRY_DELAY, WITNESS_SATS};
+use crate::manifest::{self, ManifestAsset};
+use crate::opts::OnError;
 use crate::regtest;
+use crate::report::{
+    AssetResult, BackupResult, BalanceResult, ReorgResult, SendMode, StepTimes, TransferFailure,
+    TransferResult,
+};
+use crate::restore;
+
+const STEP_SEND: &str = "send";
+const STEP_RECV_REFRESH_1: &str = "recv refresh 1";
+const STEP_SEND_REFRESH_1: &str = "send refresh 1";
+const STEP_MINING: &str = "mining";
+const STEP_ELECTRUM_WAIT: &str = "electrum wait";
+const STEP_RECV_REFRESH_2: &str = "recv refresh 2";
+const STEP_SEND_REFRESH_2: &str = "send refresh 2";
+const STEP_CHECK: &str = "check";
+const STEP_REORG: &str = "reorg";
 
 /// Wrapper for rgb-lib wallet
 pub(crate) struct WalletWrapper {
-    wallet: RefCell<Wallet>,
+    wallet: Mutex<Wallet>,
     online: Online,
     fingerprint: String,
     wallet_index: u8,
     asset_counter: u8,
+    /// Number of succeeded transfers the wallet took part in
+    transfers: AtomicU32,
 }
 
 impl Debug for WalletWrapper {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        let wallet_data = &self.wallet.borrow().get_wallet_data();
+        let wallet_data = &self.wallet.lock().unwrap().get_wallet_data();
         f.debug_struct("WalletInfo")
             .field("data_dir", &wallet_data.data_dir)
             .field("network", &wallet_data.bitcoin_network)
@@ -39,6 +61,53 @@ impl Debug for WalletWrapper {
     }
 }
 
+/// Asset held by the test wallets, with the ticker (or name, for CFA) used in reports
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct RgbAsset {
+    pub(crate) asset_id: String,
+    pub(crate) ticker: String,
+    pub(crate) schema: AssetSchema,
+}
+
+impl RgbAsset {
+    /// Amount to be sent for the requested one, UDA assets always having an amount of 1
+    pub(crate) fn amount(&self, amount: u64) -> u64 {
+        match self.schema {
+            AssetSchema::Uda => 1,
+            AssetSchema::Nia | AssetSchema::Cfa => amount,
+        }
+    }
+}
+
+pub(crate) fn schema_name(schema: AssetSchema) -> &'static str {
+    match schema {
+        AssetSchema::Nia => "nia",
+        AssetSchema::Cfa => "cfa",
+        AssetSchema::Uda => "uda",
+    }
+}
+
+fn schema_from_name(name: &str) -> AssetSchema {
+    match name {
+        "cfa" => AssetSchema::Cfa,
+        "uda" => AssetSchema::Uda,
+        _ => AssetSchema::Nia,
+    }
+}
+
+/// Options applying to each transfer
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TransferOpts {
+    /// What to do when a transfer fails
+    pub(crate) on_error: OnError,
+    /// Send without waiting for the receiver ACK, broadcasting the transaction right away
+    pub(crate) donation: bool,
+    /// Confirmations required for sent and received transfers to settle
+    pub(crate) min_confirmations: u8,
+    /// Back up each wallet every given number of succeeded transfers it takes part in
+    pub(crate) backup_every: Option<u16>,
+}
+
 pub(crate) enum TestMode {
     HandleUtxoErrors { utxos: u8, utxo_size: u32 },
     NoErrorHandling,
@@ -52,149 +121,220 @@ impl WalletWrapper {
         wallet_index: u8,
     ) -> Self {
         WalletWrapper {
-            wallet: RefCell::new(wallet),
+            wallet: Mutex::new(wallet),
             online,
             fingerprint,
             wallet_index,
             asset_counter: 0,
+            transfers: AtomicU32::new(0),
         }
     }
 
+    /// Set the number of succeeded transfers a reopened wallet took part in
+    pub(crate) fn resume_transfers(&self, transfers: u32) {
+        self.transfers.store(transfers, Ordering::Relaxed);
+    }
+
     fn send(
         &self,
         amount: u64,
         recver: &WalletWrapper,
-        asset_ids: &Vec<&str>,
+        assets: &[RgbAsset],
         test_mode: &TestMode,
         witness: bool,
-    ) -> (String, HashMap<String, String>) {
-        let mut map: HashMap<String, String> = HashMap::new();
+        transfer_opts: TransferOpts,
+    ) -> Result<(String, HashMap<String, String>), Error> {
+        let (txid, mut maps) = self.send_to(
+            amount,
+            &[(recver, witness)],
+            assets,
+            test_mode,
+            transfer_opts,
+        )?;
+        Ok((txid, maps.remove(0)))
+    }
+
+    /// Send assets to multiple receivers (each with its send mode) in a single transaction, each
+    /// receiving `amount` of each asset
+    ///
+    /// Returns the txid and, for each receiver, the recipient ID of each asset.
+    fn send_to(
+        &self,
+        amount: u64,
+        recvers: &[(&WalletWrapper, bool)],
+        assets: &[RgbAsset],
+        test_mode: &TestMode,
+        transfer_opts: TransferOpts,
+    ) -> Result<(String, Vec<HashMap<String, String>>), Error> {
+        let _span = info_span!("wallet send").entered();
+        let min_confirmations = transfer_opts.min_confirmations;
+        let mut maps: Vec<HashMap<String, String>> = vec![HashMap::new(); recvers.len()];
         let mut recipient_map = HashMap::new();
-        for asset_id in asset_ids {
-            let (receive_data, recipient_data) = if witness {
-                let witness_recv_data = recver.witness_receive();
-                let witness_data = RecipientData::WitnessData {
-                    script_buf: ScriptBuf::from_hex(&witness_recv_data.recipient_id).unwrap(),
-                    amount_sat: WITNESS_SATS as u64,
-                    blinding: None,
+        for asset in assets {
+            let mut recipients = Vec::with_capacity(recvers.len());
+            for ((recver, witness), map) in recvers.iter().zip(maps.iter_mut()) {
+                let (receive_data, recipient_data) = if *witness {
+                    let witness_recv_data = recver.witness_receive(min_confirmations)?;
+                    let witness_data = RecipientData::WitnessData {
+                        script_buf: ScriptBuf::from_hex(&witness_recv_data.recipient_id).unwrap(),
+                        amount_sat: WITNESS_SATS as u64,
+                        blinding: None,
+                    };
+                    (witness_recv_data, witness_data)
+                } else {
+                    let blind_recv_data = recver.blind_receive(test_mode, min_confirmations)?;
+                    let secret_seal = SecretSeal::from_str(&blind_recv_data.recipient_id).unwrap();
+                    let blinded_data = RecipientData::BlindedUTXO(secret_seal);
+                    (blind_recv_data, blinded_data)
                 };
-                (witness_recv_data, witness_data)
-            } else {
-                let blind_recv_data = recver.blind_receive(test_mode);
-                let secret_seal = SecretSeal::from_str(&blind_recv_data.recipient_id).unwrap();
-                let blinded_data = RecipientData::BlindedUTXO(secret_seal);
-                (blind_recv_data, blinded_data)
-            };
-            map.insert(asset_id.to_string(), receive_data.recipient_id.clone());
-            recipient_map.insert(
-                asset_id.to_string(),
-                vec![Recipient {
-                    amount,
+                map.insert(asset.asset_id.clone(), receive_data.recipient_id.clone());
+                recipients.push(Recipient {
+                    amount: asset.amount(amount),
                     recipient_data,
-                    transport_endpoints: vec![TRANSPORT_ENDPOINT.to_string()],
-                }],
-            );
+                    transport_endpoints: vec![regtest::transport_endpoint().to_string()],
+                });
+            }
+            recipient_map.insert(asset.asset_id.clone(), recipients);
         }
         let txid = loop {
-            let send_res = self.wallet.borrow().send(
+            let send_res = self.wallet.lock().unwrap().send(
                 self.online.clone(),
                 recipient_map.clone(),
-                false,
+                transfer_opts.donation,
                 FEE_RATE,
-                MIN_CONFIRMATIONS,
+                min_confirmations,
             );
             match test_mode {
                 TestMode::HandleUtxoErrors { utxos, utxo_size } => match send_res {
                     Ok(res) => break res,
                     Err(err) => {
-                        self.add_funds_utxos_on_error(err, utxos, utxo_size, false);
+                        self.add_funds_utxos_on_error(err, utxos, utxo_size, false)?;
                     }
                 },
                 TestMode::NoErrorHandling => {
-                    break send_res.unwrap();
+                    break send_res?;
                 }
             }
         };
-        (txid, map)
+        Ok((txid, maps))
     }
 
-    fn refresh(&self) -> bool {
+    fn refresh(&self) -> Result<bool, Error> {
+        let _span = info_span!("wallet refresh").entered();
         self.wallet
-            .borrow()
-            .refresh(self.online.clone(), None, vec![])
+            .lock()
             .unwrap()
+            .refresh(self.online.clone(), None, vec![])
     }
 
-    fn blind_receive(&self, test_mode: &TestMode) -> ReceiveData {
+    fn blind_receive(
+        &self,
+        test_mode: &TestMode,
+        min_confirmations: u8,
+    ) -> Result<ReceiveData, Error> {
+        let _span = info_span!("wallet blind receive").entered();
         loop {
-            let blind_res = self.wallet.borrow().blind_receive(
+            let blind_res = self.wallet.lock().unwrap().blind_receive(
                 None,
                 None,
                 None,
-                vec![TRANSPORT_ENDPOINT.to_string()],
-                MIN_CONFIRMATIONS,
+                vec![regtest::transport_endpoint().to_string()],
+                min_confirmations,
             );
             match test_mode {
                 TestMode::HandleUtxoErrors { utxos, utxo_size } => match blind_res {
-                    Ok(res) => return res,
+                    Ok(res) => return Ok(res),
                     Err(err) => {
-                        self.add_funds_utxos_on_error(err, utxos, utxo_size, true);
+                        self.add_funds_utxos_on_error(err, utxos, utxo_size, true)?;
                     }
                 },
                 TestMode::NoErrorHandling => {
-                    break blind_res.unwrap();
+                    break blind_res;
                 }
             }
         }
     }
 
-    fn witness_receive(&self) -> ReceiveData {
-        self.wallet
-            .borrow_mut()
-            .witness_receive(
-                None,
-                None,
-                None,
-                vec![TRANSPORT_ENDPOINT.to_string()],
-                MIN_CONFIRMATIONS,
-            )
-            .unwrap()
+    fn witness_receive(&self, min_confirmations: u8) -> Result<ReceiveData, Error> {
+        let _span = info_span!("wallet witness receive").entered();
+        self.wallet.lock().unwrap().witness_receive(
+            None,
+            None,
+            None,
+            vec![regtest::transport_endpoint().to_string()],
+            min_confirmations,
+        )
     }
 
-    fn check_transfer(&self, map: &HashMap<String, String>) {
+    fn check_transfer(&self, map: &HashMap<String, String>) -> Result<(), TransferFailure> {
+        let _span = info_span!("wallet list transfers").entered();
         for (asset_id, blinded_utxo) in map {
             let transfers = self
                 .wallet
-                .borrow()
+                .lock()
+                .unwrap()
                 .list_transfers(Some(asset_id.to_string()))
-                .unwrap();
+                .map_err(|e| rgb_failure(STEP_CHECK, &e))?;
             let transfer = transfers
                 .iter()
                 .find(|t| t.recipient_id == Some(blinded_utxo.to_string()))
-                .unwrap();
-            assert_eq!(transfer.status, TransferStatus::Settled);
+                .ok_or_else(|| {
+                    TransferFailure::new(
+                        STEP_CHECK,
+                        "TransferNotFound",
+                        format!("no transfer of asset {asset_id} to {blinded_utxo}"),
+                    )
+                })?;
+            if transfer.status != TransferStatus::Settled {
+                return Err(TransferFailure::new(
+                    STEP_CHECK,
+                    "NotSettled",
+                    format!(
+                        "transfer of asset {asset_id} is {:?} for wallet {}",
+                        transfer.status, self.fingerprint
+                    ),
+                ));
+            }
         }
+        Ok(())
+    }
+
+    /// Settle the transfers an interrupted run left confirmed and fail the ones still waiting for
+    /// the counterparty, releasing the allocations they hold
+    pub(crate) fn recover_transfers(&self) {
+        let _span = info_span!("wallet recover transfers").entered();
+        let wallet = self.wallet.lock().unwrap();
+        wallet
+            .refresh(self.online.clone(), None, vec![])
+            .expect("wallet should have been refreshed");
+        wallet
+            .fail_transfers(self.online.clone(), None, false)
+            .expect("pending transfers should have been failed");
     }
 
     pub(crate) fn create_utxos(&self, num: u8, size: u32, up_to: bool) {
+        let _span = info_span!("wallet create utxos").entered();
         self.wallet
-            .borrow()
+            .lock()
+            .unwrap()
             .create_utxos(self.online.clone(), up_to, Some(num), Some(size), FEE_RATE)
             .unwrap();
     }
 
     pub(crate) fn fund(&self, amt: u32) {
-        let address = self.wallet.borrow().get_address().unwrap();
-        let fund_amount = amt as f32 / 100_000_000f32;
-        regtest::fund_wallet(&address, &fund_amount.to_string());
-        regtest::mine();
-        std::thread::sleep(std::time::Duration::from_millis(1000));
+        let _span = info_span!("wallet fund").entered();
+        let address = self.wallet.lock().unwrap().get_address().unwrap();
+        regtest::fund_wallet(&address, amt as u64);
+        regtest::mine().expect("failed to mine");
+        regtest::wait_electrum_sync().expect("electrum server should have synced");
     }
 
     pub(crate) fn show_unspents_with_allocations(&self) {
+        let _span = info_span!("wallet list unspents").entered();
         let unspents = self
             .wallet
-            .borrow()
+            .lock()
+            .unwrap()
             .list_unspents(Some(self.online.clone()), true)
             .unwrap();
         for unspent in unspents {
@@ -217,27 +357,137 @@ impl WalletWrapper {
         }
     }
 
-    /// Issue asset with unique name
-    pub(crate) fn issue_nia(&mut self, amounts: Vec<u64>, test_mode: &TestMode) -> AssetNIA {
-        self.asset_counter += 1;
-        let ticker = format!("T{}{}", self.wallet_index, self.asset_counter);
-        loop {
-            let issue_res = self.wallet.borrow().issue_asset_nia(
+    /// Issue an asset of the given schema, CFA assets carrying a media file of `media_size` bytes
+    pub(crate) fn issue(
+        &mut self,
+        schema: AssetSchema,
+        amounts: Vec<u64>,
+        media_size: u32,
+        test_mode: &TestMode,
+    ) -> RgbAsset {
+        let _span = info_span!("wallet issue").entered();
+        // a resumed run reuses the assets issued before it was interrupted
+        if let Some(asset) = manifest::replay_asset() {
+            self.asset_counter += 1;
+            return RgbAsset {
+                asset_id: asset.asset_id,
+                ticker: asset.ticker,
+                schema: schema_from_name(&asset.schema),
+            };
+        }
+        let asset = match schema {
+            AssetSchema::Nia => self.issue_nia(amounts, test_mode),
+            AssetSchema::Cfa => self.issue_cfa(amounts, media_size, test_mode),
+            AssetSchema::Uda => self.issue_uda(test_mode),
+        };
+        manifest::record_asset(ManifestAsset {
+            asset_id: asset.asset_id.clone(),
+            ticker: asset.ticker.clone(),
+            schema: schema_name(asset.schema).to_string(),
+        });
+        asset
+    }
+
+    /// Issue NIA asset with unique ticker
+    pub(crate) fn issue_nia(&mut self, amounts: Vec<u64>, test_mode: &TestMode) -> RgbAsset {
+        let ticker = self.next_ticker("T");
+        let asset = self.with_utxo_handling(test_mode, |wallet| {
+            wallet.issue_asset_nia(
                 self.online.clone(),
                 ticker.clone(),
                 "name".to_string(),
                 0,
                 amounts.clone(),
-            );
+            )
+        });
+        RgbAsset {
+            asset_id: asset.asset_id,
+            ticker: asset.ticker,
+            schema: AssetSchema::Nia,
+        }
+    }
+
+    /// Issue CFA asset with unique name, carrying a media file of `media_size` bytes (if not 0)
+    pub(crate) fn issue_cfa(
+        &mut self,
+        amounts: Vec<u64>,
+        media_size: u32,
+        test_mode: &TestMode,
+    ) -> RgbAsset {
+        let name = self.next_ticker("C");
+        let media_path = (media_size > 0).then(|| self.write_media_file(&name, media_size));
+        let asset = self.with_utxo_handling(test_mode, |wallet| {
+            wallet.issue_asset_cfa(
+                self.online.clone(),
+                name.clone(),
+                None,
+                0,
+                amounts.clone(),
+                media_path.clone(),
+            )
+        });
+        RgbAsset {
+            asset_id: asset.asset_id,
+            ticker: asset.name,
+            schema: AssetSchema::Cfa,
+        }
+    }
+
+    /// Issue UDA asset with unique ticker
+    pub(crate) fn issue_uda(&mut self, test_mode: &TestMode) -> RgbAsset {
+        let ticker = self.next_ticker("U");
+        let asset = self.with_utxo_handling(test_mode, |wallet| {
+            wallet.issue_asset_uda(
+                self.online.clone(),
+                ticker.clone(),
+                "name".to_string(),
+                None,
+                0,
+                None,
+                vec![],
+            )
+        });
+        RgbAsset {
+            asset_id: asset.asset_id,
+            ticker: asset.ticker,
+            schema: AssetSchema::Uda,
+        }
+    }
+
+    fn next_ticker(&mut self, prefix: &str) -> String {
+        self.asset_counter += 1;
+        format!("{prefix}{}{}", self.wallet_index, self.asset_counter)
+    }
+
+    /// Write a media file of random bytes, so its content cannot be deduplicated or compressed
+    fn write_media_file(&self, name: &str, size: u32) -> String {
+        let mut media_path = PathBuf::from(&self.wallet.lock().unwrap().get_wallet_data().data_dir);
+        media_path.push("media");
+        std::fs::create_dir_all(&media_path).expect("media dir should have been created");
+        media_path.push(format!("{}_{name}", self.fingerprint));
+        let content: Vec<u8> = (0..size).map(|_| rand::random()).collect();
+        std::fs::write(&media_path, content).expect("media file should have been written");
+        media_path.to_string_lossy().to_string()
+    }
+
+    /// Run a wallet operation, funding the wallet and creating UTXOs as needed if requested
+    fn with_utxo_handling<T>(
+        &self,
+        test_mode: &TestMode,
+        op: impl Fn(&Wallet) -> Result<T, Error>,
+    ) -> T {
+        loop {
+            let res = op(&self.wallet.lock().unwrap());
             match test_mode {
-                TestMode::HandleUtxoErrors { utxos, utxo_size } => match issue_res {
-                    Ok(asset) => return asset,
+                TestMode::HandleUtxoErrors { utxos, utxo_size } => match res {
+                    Ok(res) => return res,
                     Err(err) => {
-                        self.add_funds_utxos_on_error(err, utxos, utxo_size, true);
+                        self.add_funds_utxos_on_error(err, utxos, utxo_size, true)
+                            .unwrap_or_else(|err| panic!("Unexpected error: {err}"));
                     }
                 },
                 TestMode::NoErrorHandling => {
-                    return issue_res.unwrap();
+                    return res.unwrap();
                 }
             }
         }
@@ -249,25 +499,154 @@ impl WalletWrapper {
         utxos: &u8,
         utxo_size: &u32,
         create_utxos_up_to: bool,
-    ) {
+    ) -> Result<(), Error> {
         match err {
             Error::InsufficientBitcoins { needed, .. } => {
                 self.fund(needed as u32 * 5);
                 // sync
                 _ = self
                     .wallet
-                    .borrow()
+                    .lock()
+                    .unwrap()
                     .list_unspents(Some(self.online.clone()), true);
             }
             Error::InsufficientAllocationSlots => {
                 self.create_utxos(*utxos, *utxo_size, create_utxos_up_to);
             }
-            _ => panic!("Unexpected error: {err}"),
+            _ => return Err(err),
+        }
+        Ok(())
+    }
+
+    /// Status of the transfer of an asset to the given recipient, if the wallet knows about it
+    fn transfer_status(
+        &self,
+        asset_id: &str,
+        recipient_id: &str,
+    ) -> Result<Option<TransferStatus>, Error> {
+        let _span = info_span!("wallet list transfers").entered();
+        let transfers = self
+            .wallet
+            .lock()
+            .unwrap()
+            .list_transfers(Some(asset_id.to_string()))?;
+        Ok(transfers
+            .into_iter()
+            .find(|t| t.recipient_id.as_deref() == Some(recipient_id))
+            .map(|t| t.status))
+    }
+
+    /// Balance of an asset, zero if the wallet doesn't hold it
+    fn asset_balance(&self, asset_id: &str) -> BalanceResult {
+        let assets = self.list_assets();
+        let balances = assets
+            .nia
+            .unwrap_or_default()
+            .into_iter()
+            .map(|a| (a.asset_id, a.balance))
+            .chain(
+                assets
+                    .cfa
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|a| (a.asset_id, a.balance)),
+            )
+            .chain(
+                assets
+                    .uda
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|a| (a.asset_id, a.balance)),
+            );
+        balances
+            .filter(|(id, _)| id == asset_id)
+            .map(|(_, b)| BalanceResult {
+                settled: b.settled,
+                future: b.future,
+                spendable: b.spendable,
+            })
+            .next()
+            .unwrap_or_default()
+    }
+
+    /// Back up the wallet to `backups/<fingerprint>/<transfers>.backup` in the data dir
+    fn backup(&self, transfers: u32) -> BackupResult {
+        let _span = info_span!("wallet backup").entered();
+        let wallet = self.wallet.lock().unwrap();
+        let backup_dir = Path::new(&wallet.get_wallet_data().data_dir)
+            .join("backups")
+            .join(&self.fingerprint);
+        std::fs::create_dir_all(&backup_dir).expect("backup dir should have been created");
+        let backup_path = backup_dir.join(format!("{transfers}.backup"));
+        let t_begin = timestamp();
+        wallet
+            .backup(backup_path.to_str().unwrap(), BACKUP_PASSWORD)
+            .unwrap();
+        let time = millis(timestamp() - t_begin);
+        restore::record_backup_state(&wallet, &backup_path);
+        BackupResult {
+            wallet: self.fingerprint.clone(),
+            transfers,
+            size: std::fs::metadata(&backup_path)
+                .expect("backup file should exist")
+                .len(),
+            time,
         }
     }
 
-    pub(crate) fn list_assets(&self) -> Assets {
-        self.wallet.borrow().list_assets(Vec::new()).unwrap()
+    /// Build and sign a transaction draining all the wallet UTXOs, including colored ones, to the
+    /// miner, returning it without broadcasting it
+    pub(crate) fn conflicting_tx(&self) -> String {
+        let _span = info_span!("wallet drain").entered();
+        let wallet = self.wallet.lock().unwrap();
+        let psbt = wallet
+            .drain_to_begin(
+                self.online.clone(),
+                regtest::miner_address().to_string(),
+                true,
+                FEE_RATE,
+            )
+            .unwrap();
+        let signed_psbt = wallet.sign_psbt(psbt).unwrap();
+        regtest::finalize_psbt(&signed_psbt).expect("conflicting tx should have been finalized")
+    }
+
+    fn list_assets(&self) -> Assets {
+        let _span = info_span!("wallet list assets").entered();
+        self.wallet.lock().unwrap().list_assets(Vec::new()).unwrap()
+    }
+
+    /// Assets with spendable balance, with the spendable amount
+    pub(crate) fn spendable_assets(&self) -> Vec<(RgbAsset, u64)> {
+        let assets = self.list_assets();
+        let nia = assets.nia.unwrap_or_default().into_iter().map(|a| {
+            let asset = RgbAsset {
+                asset_id: a.asset_id,
+                ticker: a.ticker,
+                schema: AssetSchema::Nia,
+            };
+            (asset, a.balance.spendable)
+        });
+        let cfa = assets.cfa.unwrap_or_default().into_iter().map(|a| {
+            let asset = RgbAsset {
+                asset_id: a.asset_id,
+                ticker: a.name,
+                schema: AssetSchema::Cfa,
+            };
+            (asset, a.balance.spendable)
+        });
+        let uda = assets.uda.unwrap_or_default().into_iter().map(|a| {
+            let asset = RgbAsset {
+                asset_id: a.asset_id,
+                ticker: a.ticker,
+                schema: AssetSchema::Uda,
+            };
+            (asset, a.balance.spendable)
+        });
+        nia.chain(cfa)
+            .chain(uda)
+            .filter(|(_, spendable)| *spendable > 0)
+            .collect()
     }
 }
 
@@ -282,115 +661,1078 @@ fn get_consignment_path(data_dir: &str, fingerprint: &str, txid: &str, asset_id:
     consignment_path.to_string_lossy().to_string()
 }
 
-fn get_consignment_size(consignment_path: &str) -> u64 {
-    let metadata = std::fs::metadata(consignment_path).unwrap();
-    metadata.len()
+fn get_consignment_size(consignment_path: &str) -> Result<u64, TransferFailure> {
+    let metadata = std::fs::metadata(consignment_path)
+        .map_err(|e| TransferFailure::new(STEP_CHECK, "ConsignmentNotFound", e.to_string()))?;
+    Ok(metadata.len())
+}
+
+thread_local! {
+    /// Transfer progress of the current thread, buffered when transfers run concurrently
+    static PROGRESS: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Buffer the transfer progress of the current thread, printing it as a whole line at the end of
+/// each transfer so the output of concurrent transfers doesn't get mixed
+pub(crate) fn buffer_progress() {
+    PROGRESS.with(|p| *p.borrow_mut() = Some(String::new()));
+}
+
+/// Print transfer progress, or add it to the buffered line
+pub(crate) fn progress(text: &str) {
+    PROGRESS.with(|p| match p.borrow_mut().as_mut() {
+        Some(buffer) => buffer.push_str(text),
+        None => {
+            print!("{text}");
+            std::io::stdout().flush().unwrap();
+        }
+    });
+}
+
+/// End the progress line of a transfer
+fn end_progress() {
+    PROGRESS.with(|p| match p.borrow_mut().as_mut() {
+        Some(buffer) => println!("{}", std::mem::take(buffer)),
+        None => println!(),
+    });
+}
+
+/// Failure of an rgb-lib operation, with the name of the error variant
+fn rgb_failure(step: &str, err: &Error) -> TransferFailure {
+    let debug = format!("{err:?}");
+    let variant = debug
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .next()
+        .unwrap_or_default();
+    TransferFailure::new(step, variant, err.to_string())
 }
 
+fn mining_failure(message: String) -> TransferFailure {
+    TransferFailure::new(STEP_MINING, "MiningFailed", message)
+}
+
+fn electrum_failure(message: String) -> TransferFailure {
+    TransferFailure::new(STEP_ELECTRUM_WAIT, "NotSynced", message)
+}
+
+/// Run a transfer step in a span labelled with its name, retrying it on failure up to the given
+/// number of times
+fn with_retries<T>(
+    retries: u8,
+    step_name: &str,
+    mut step: impl FnMut(u8) -> Result<T, TransferFailure>,
+) -> Result<T, TransferFailure> {
+    let _span = info_span!("step", label = step_name).entered();
+    let mut attempt = 0;
+    loop {
+        match step(attempt) {
+            Err(failure) if attempt < retries => {
+                attempt += 1;
+                progress(&format!(
+                    " ({} failed, retry {attempt}/{retries})",
+                    failure.step
+                ));
+                std::thread::sleep(RETRY_DELAY);
+            }
+            res => return res,
+        }
+    }
+}
+
+/// Carry out a transfer, recording failures in the returned result instead of panicking
+///
+/// If the error policy is set to retry, failed steps are retried before giving up. Donations skip
+/// the ACK refreshes, as they are broadcast on send.
 pub(crate) fn send_assets(
     sender: &WalletWrapper,
     recver: &WalletWrapper,
-    assets: &[(String, String)],
+    assets: &[RgbAsset],
     amount: u64,
     test_mode: &TestMode,
     witness: bool,
-) -> String {
-    let data_dir = &sender.wallet.borrow().get_wallet_data().data_dir;
+    transfer_opts: TransferOpts,
+) -> TransferResult {
+    if let Some(mut failures) = manifest::replay_transfers(1) {
+        return replayed_result(sender, recver, witness, transfer_opts, failures.remove(0));
+    }
+    progress(&format!(
+        "  {}->{} ",
+        sender.fingerprint, recver.fingerprint
+    ));
 
-    print!("  {}->{} ", sender.fingerprint, recver.fingerprint);
-    std::io::stdout().flush().unwrap();
+    let mut result = new_result(sender, recver, witness, transfer_opts.donation);
+    let t_begin = timestamp();
+    if let Err(failure) = transfer_steps(
+        &mut result,
+        sender,
+        recver,
+        assets,
+        amount,
+        test_mode,
+        transfer_opts,
+    ) {
+        record_failure(&mut result, failure, t_begin);
+    } else {
+        backup_wallets(&mut result, [sender, recver], transfer_opts.backup_every);
+    }
+    result
+}
+
+/// Back up the wallets taking part in a succeeded transfer, each every `backup_every` transfers
+fn backup_wallets(
+    result: &mut TransferResult,
+    wallets: [&WalletWrapper; 2],
+    backup_every: Option<u16>,
+) {
+    let Some(every) = backup_every else {
+        return;
+    };
+    for wallet in wallets {
+        let transfers = wallet.transfers.fetch_add(1, Ordering::Relaxed) + 1;
+        if transfers.is_multiple_of(every as u32) {
+            let backup = wallet.backup(transfers);
+            progress(&format!(
+                "  {} backup[{:6}] {} bytes",
+                wallet.fingerprint, backup.time, backup.size
+            ));
+            end_progress();
+            result.backups.push(backup);
+        }
+    }
+}
+
+fn new_result(
+    sender: &WalletWrapper,
+    recver: &WalletWrapper,
+    witness: bool,
+    donation: bool,
+) -> TransferResult {
+    TransferResult {
+        params: BTreeMap::new(),
+        sender: sender.fingerprint.clone(),
+        receiver: recver.fingerprint.clone(),
+        mode: SendMode::from_witness(witness),
+        donation,
+        times: StepTimes::default(),
+        txid: String::new(),
+        assets: vec![],
+        failure: None,
+        reorg: None,
+        backups: vec![],
+        replayed: false,
+    }
+}
+
+/// Result of a transfer completed before a resumed run was interrupted, with the failure it had,
+/// not written again
+fn replayed_result(
+    sender: &WalletWrapper,
+    recver: &WalletWrapper,
+    witness: bool,
+    transfer_opts: TransferOpts,
+    failure: Option<TransferFailure>,
+) -> TransferResult {
+    TransferResult {
+        replayed: true,
+        failure,
+        ..new_result(sender, recver, witness, transfer_opts.donation)
+    }
+}
+
+fn retries(on_error: OnError) -> u8 {
+    match on_error {
+        OnError::Retry(retries) => retries,
+        OnError::Abort | OnError::Continue => 0,
+    }
+}
+
+fn record_failure(result: &mut TransferResult, failure: TransferFailure, t_begin: Instant) {
+    result.times.total = millis(timestamp() - t_begin);
+    progress(&format!(
+        " FAILED at {}: {} ({})",
+        failure.step, failure.error, failure.message
+    ));
+    end_progress();
+    result.failure = Some(failure);
+}
+
+fn transfer_steps(
+    result: &mut TransferResult,
+    sender: &WalletWrapper,
+    recver: &WalletWrapper,
+    assets: &[RgbAsset],
+    amount: u64,
+    test_mode: &TestMode,
+    transfer_opts: TransferOpts,
+) -> Result<(), TransferFailure> {
+    let witness = result.mode == SendMode::Witness;
+    let retries = retries(transfer_opts.on_error);
+
+    let (map, t_begin, _) = send_and_ack(
+        result,
+        sender,
+        recver,
+        assets,
+        amount,
+        test_mode,
+        transfer_opts,
+    )?;
+    let txid = result.txid.clone();
+    progress(" mining");
+    if !regtest::get_raw_mempool().contains(&txid) {
+        return Err(TransferFailure::new(
+            STEP_MINING,
+            "NotBroadcast",
+            format!("transaction {txid} is not in the mempool"),
+        ));
+    }
+    let t_mine_begin = timestamp();
+    regtest::mine().map_err(mining_failure)?;
+    let t_mine = timestamp();
+    result.times.mining = millis(t_mine - t_mine_begin);
+    progress(&format!("[{:6}], electrum", result.times.mining));
+    regtest::wait_electrum_sync().map_err(electrum_failure)?;
+    let t_synced = timestamp();
+    result.times.electrum_wait = millis(t_synced - t_mine);
+    progress(&format!("[{:6}], receiver", result.times.electrum_wait));
+    let t_end = settle_refreshes(result, sender, recver, retries, t_synced)?;
+    result.times.total = millis(t_end - t_begin);
+    progress(&format!(" > {:6} total", result.times.total));
+    progress(&format!(" {}", if witness { "w" } else { "b" }));
+    if transfer_opts.donation {
+        progress("d");
+    }
+
+    result.assets = asset_results(sender, &txid, assets, &map)?;
+    progress(&format!(" assets: {}", assets_info(&result.assets)));
+    check_settled(sender, recver, &map, retries)?;
+    end_progress();
+    Ok(())
+}
+
+/// Send a transfer and refresh the receiver and sender to process the ACK and broadcast it
+///
+/// Returns the recipient ID of each asset, the time the send started and the time the transfer
+/// was broadcast.
+fn send_and_ack(
+    result: &mut TransferResult,
+    sender: &WalletWrapper,
+    recver: &WalletWrapper,
+    assets: &[RgbAsset],
+    amount: u64,
+    test_mode: &TestMode,
+    transfer_opts: TransferOpts,
+) -> Result<(HashMap<String, String>, Instant, Instant), TransferFailure> {
+    let witness = result.mode == SendMode::Witness;
+    let retries = retries(transfer_opts.on_error);
 
-    let asset_ids = assets
-        .iter()
-        .map(|(id, _)| id.as_str())
-        .collect::<Vec<&str>>();
     let t_begin = timestamp();
-    let (txid, map) = sender.send(amount, recver, &asset_ids, test_mode, witness);
+    let (txid, map) = with_retries(retries, STEP_SEND, |_| {
+        sender
+            .send(amount, recver, assets, test_mode, witness, transfer_opts)
+            .map_err(|e| rgb_failure(STEP_SEND, &e))
+    })?;
     let t_send = timestamp();
     assert!(!txid.is_empty());
+    result.txid = txid;
+    result.times.send = millis(t_send - t_begin);
+    progress(&format!("send[{:6}] >", result.times.send));
 
-    // take transfers from WaitingCounterparty to Settled
-    print!(
-        "send[{:6}] > refreshing: receiver",
-        (t_send - t_begin).as_millis()
-    );
-    std::io::stdout().flush().unwrap();
-    recver.refresh();
+    // take transfers from WaitingCounterparty to Settled, donations are broadcast on send
+    if transfer_opts.donation {
+        return Ok((map, t_begin, t_send));
+    }
+    progress(" refreshing: receiver");
+    with_retries(retries, STEP_RECV_REFRESH_1, |_| {
+        recver
+            .refresh()
+            .map_err(|e| rgb_failure(STEP_RECV_REFRESH_1, &e))
+    })?;
     let t_ref_recv_1 = timestamp();
-    print!("[{:6}]", (t_ref_recv_1 - t_send).as_millis());
-    print!(", sender");
-    std::io::stdout().flush().unwrap();
-    sender.refresh();
+    result.times.recv_refresh_1 = millis(t_ref_recv_1 - t_send);
+    progress(&format!("[{:6}]", result.times.recv_refresh_1));
+    progress(", sender");
+    with_retries(retries, STEP_SEND_REFRESH_1, |_| {
+        sender
+            .refresh()
+            .map_err(|e| rgb_failure(STEP_SEND_REFRESH_1, &e))
+    })?;
     let t_ref_send_1 = timestamp();
-    print!("[{:6}]", (t_ref_send_1 - t_ref_recv_1).as_millis());
-    print!(", mining");
-    std::io::stdout().flush().unwrap();
-    regtest::mine();
-    let t_mine = timestamp();
-    print!(", receiver");
-    std::io::stdout().flush().unwrap();
-    recver.refresh();
+    result.times.send_refresh_1 = millis(t_ref_send_1 - t_ref_recv_1);
+    progress(&format!("[{:6}],", result.times.send_refresh_1));
+    Ok((map, t_begin, t_ref_send_1))
+}
+
+/// Refresh the receiver and sender to settle a broadcast transfer, returning the end time
+fn settle_refreshes(
+    result: &mut TransferResult,
+    sender: &WalletWrapper,
+    recver: &WalletWrapper,
+    retries: u8,
+    t_start: Instant,
+) -> Result<Instant, TransferFailure> {
+    with_retries(retries, STEP_RECV_REFRESH_2, |_| {
+        recver
+            .refresh()
+            .map_err(|e| rgb_failure(STEP_RECV_REFRESH_2, &e))
+    })?;
     let t_ref_recv_2 = timestamp();
-    print!("[{:6}]", (t_ref_recv_2 - t_mine).as_millis());
-    print!(", sender");
-    std::io::stdout().flush().unwrap();
-    sender.refresh();
+    result.times.recv_refresh_2 = millis(t_ref_recv_2 - t_start);
+    progress(&format!("[{:6}]", result.times.recv_refresh_2));
+    progress(", sender");
+    with_retries(retries, STEP_SEND_REFRESH_2, |_| {
+        sender
+            .refresh()
+            .map_err(|e| rgb_failure(STEP_SEND_REFRESH_2, &e))
+    })?;
     let t_end = timestamp();
-    print!("[{:6}]", (t_end - t_ref_recv_2).as_millis());
-    print!(" > {:6} total", (t_end - t_begin).as_millis());
-    std::io::stdout().flush().unwrap();
-    print!(" {}", if witness { "w" } else { "b" });
-    std::io::stdout().flush().unwrap();
-
-    // ticker, consignment size and recipient ID
-    let mut sent_asset_info: Vec<(String, String, String)> =
-        Vec::with_capacity(asset_ids.clone().len());
-    for (asset_id, asset_ticker) in assets {
-        let consignment_path = get_consignment_path(data_dir, &sender.fingerprint, &txid, asset_id);
-        let consignment_size = get_consignment_size(&consignment_path);
-        sent_asset_info.push((
-            asset_ticker.to_string(),
-            consignment_size.to_string(),
-            map[asset_id].to_string(),
-        ))
-    }
-
-    println!(
-        " assets: {}",
-        sent_asset_info
+    result.times.send_refresh_2 = millis(t_end - t_ref_recv_2);
+    progress(&format!("[{:6}]", result.times.send_refresh_2));
+    Ok(t_end)
+}
+
+/// Reorg out the block confirming a completed transfer of an asset, mining a competing chain of
+/// `blocks` blocks, optionally including a conflicting spend of the transfer inputs
+///
+/// Both wallets are then refreshed and their transfer status and asset balance are recorded in
+/// the result. Failures to reorg or refresh are recorded as failures of the transfer.
+pub(crate) fn reorg_transfer(
+    result: &mut TransferResult,
+    sender: &WalletWrapper,
+    recver: &WalletWrapper,
+    asset: &RgbAsset,
+    blocks: u32,
+    conflict: Option<&str>,
+    transfer_opts: TransferOpts,
+) {
+    if result.replayed {
+        return;
+    }
+    progress(&format!(
+        "  {}->{} reorg",
+        sender.fingerprint, recver.fingerprint
+    ));
+    match reorg_steps(
+        result,
+        sender,
+        recver,
+        asset,
+        blocks,
+        conflict,
+        retries(transfer_opts.on_error),
+    ) {
+        Ok(reorg) => {
+            progress(&format!(
+                " > sender: {} {}, receiver: {} {}",
+                reorg.sender_status,
+                reorg.sender_balance.settled,
+                reorg.receiver_status,
+                reorg.receiver_balance.settled
+            ));
+            end_progress();
+            result.reorg = Some(reorg);
+        }
+        Err(failure) => {
+            let total = result.times.total;
+            record_failure(result, failure, timestamp());
+            result.times.total = total;
+        }
+    }
+}
+
+fn reorg_steps(
+    result: &TransferResult,
+    sender: &WalletWrapper,
+    recver: &WalletWrapper,
+    asset: &RgbAsset,
+    blocks: u32,
+    conflict: Option<&str>,
+    retries: u8,
+) -> Result<ReorgResult, TransferFailure> {
+    let reorg_err = |e: String| TransferFailure::new(STEP_REORG, "Bitcoind", e);
+    let hash = regtest::get_best_block_hash().map_err(reorg_err)?;
+    if !regtest::get_block_txids(&hash)
+        .map_err(reorg_err)?
+        .contains(&result.txid)
+    {
+        return Err(TransferFailure::new(
+            STEP_REORG,
+            "NotInBestBlock",
+            format!("transaction {} is not in the best block", result.txid),
+        ));
+    }
+    regtest::invalidate_block(&hash).map_err(reorg_err)?;
+    regtest::mine_competing_chain(blocks, conflict).map_err(reorg_err)?;
+    regtest::wait_electrum_sync().map_err(electrum_failure)?;
+
+    let recipient_id = result
+        .assets
+        .iter()
+        .find(|a| a.ticker == asset.ticker)
+        .map(|a| a.recipient_id.clone())
+        .unwrap_or_default();
+    let state = |wallet: &WalletWrapper| {
+        with_retries(retries, STEP_REORG, |_| {
+            wallet.refresh().map_err(|e| rgb_failure(STEP_REORG, &e))?;
+            let status = wallet
+                .transfer_status(&asset.asset_id, &recipient_id)
+                .map_err(|e| rgb_failure(STEP_REORG, &e))?;
+            Ok((
+                status.map_or("Missing".to_string(), |s| format!("{s:?}")),
+                wallet.asset_balance(&asset.asset_id),
+            ))
+        })
+    };
+    let (receiver_status, receiver_balance) = state(recver)?;
+    let (sender_status, sender_balance) = state(sender)?;
+    Ok(ReorgResult {
+        conflict: conflict.is_some(),
+        sender_status,
+        receiver_status,
+        sender_balance,
+        receiver_balance,
+    })
+}
+
+/// Ticker, schema, consignment size and recipient ID of the sent assets
+fn asset_results(
+    sender: &WalletWrapper,
+    txid: &str,
+    assets: &[RgbAsset],
+    map: &HashMap<String, String>,
+) -> Result<Vec<AssetResult>, TransferFailure> {
+    let data_dir = &sender.wallet.lock().unwrap().get_wallet_data().data_dir;
+    let mut sent_asset_info: Vec<AssetResult> = Vec::with_capacity(assets.len());
+    for asset in assets {
+        let consignment_path =
+            get_consignment_path(data_dir, &sender.fingerprint, txid, &asset.asset_id);
+        let consignment_size = get_consignment_size(&consignment_path)?;
+        sent_asset_info.push(AssetResult {
+            ticker: asset.ticker.clone(),
+            schema: schema_name(asset.schema).to_string(),
+            consignment_size,
+            recipient_id: map[&asset.asset_id].to_string(),
+        })
+    }
+    Ok(sent_asset_info)
+}
+
+fn assets_info(assets: &[AssetResult]) -> String {
+    assets
+        .iter()
+        .map(|a| format!("{}:{}", a.ticker, a.consignment_size))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// Check transfers have settled, refreshing again before retrying
+fn check_settled(
+    sender: &WalletWrapper,
+    recver: &WalletWrapper,
+    map: &HashMap<String, String>,
+    retries: u8,
+) -> Result<(), TransferFailure> {
+    with_retries(retries, STEP_CHECK, |attempt| {
+        if attempt > 0 {
+            for wallet in [recver, sender] {
+                wallet.refresh().map_err(|e| rgb_failure(STEP_CHECK, &e))?;
+            }
+        }
+        sender.check_transfer(map)?;
+        recver.check_transfer(map)
+    })
+}
+
+/// Hop of a chain of unconfirmed transfers
+pub(crate) struct ChainHop<'a> {
+    pub(crate) sender: &'a WalletWrapper,
+    pub(crate) recver: &'a WalletWrapper,
+    pub(crate) witness: bool,
+}
+
+/// Pass assets through a chain of wallets without mining between hops, then confirm all
+/// transfers with a single block
+///
+/// Each hop spends the still unconfirmed allocations received in the previous one, so transfers
+/// need to settle with 0 confirmations. The 3rd and 4th refreshes settle each hop before mining,
+/// while the time of mining is shared, so it is reported for each transfer of the chain. The
+/// chain stops at the first failed hop, as the following ones have no assets to send.
+pub(crate) fn send_assets_chain(
+    hops: &[ChainHop],
+    assets: &[RgbAsset],
+    amount: u64,
+    test_mode: &TestMode,
+    transfer_opts: TransferOpts,
+) -> Vec<TransferResult> {
+    if let Some(failures) = manifest::replay_transfers(hops.len()) {
+        return hops
             .iter()
-            .map(|(ticker, cons_size, _)| format!("{ticker}:{cons_size}"))
-            .collect::<Vec<String>>()
-            .join(" ")
-    );
+            .zip(failures)
+            .map(|(h, failure)| {
+                replayed_result(h.sender, h.recver, h.witness, transfer_opts, failure)
+            })
+            .collect();
+    }
+    let retries = retries(transfer_opts.on_error);
+    let mut results = vec![];
+    // sent transfers, with their recipient IDs
+    let mut sent: Vec<Option<BatchSent>> = vec![];
+
+    for (depth, hop) in hops.iter().enumerate() {
+        progress(&format!(
+            "  [{:2}] {}->{} ",
+            depth + 1,
+            hop.sender.fingerprint,
+            hop.recver.fingerprint
+        ));
+        let mut result = new_result(hop.sender, hop.recver, hop.witness, transfer_opts.donation);
+        let t_hop = timestamp();
+        let hop_res = send_and_ack(
+            &mut result,
+            hop.sender,
+            hop.recver,
+            assets,
+            amount,
+            test_mode,
+            transfer_opts,
+        )
+        .and_then(|(map, t_begin, t_acked)| {
+            progress(" settling: receiver");
+            let t_end = settle_refreshes(&mut result, hop.sender, hop.recver, retries, t_acked)?;
+            result.times.total = millis(t_end - t_begin);
+            progress(&format!(
+                " > {:6} {}",
+                result.times.total,
+                if hop.witness { "w" } else { "b" }
+            ));
+            end_progress();
+            Ok(BatchSent { t_begin, map })
+        });
+        match hop_res {
+            Ok(hop_sent) => {
+                results.push(result);
+                sent.push(Some(hop_sent));
+            }
+            Err(failure) => {
+                record_failure(&mut result, failure, t_hop);
+                results.push(result);
+                break;
+            }
+        }
+    }
+
+    if sent.is_empty() {
+        return results;
+    }
+    progress("  mining");
+    let mempool = regtest::get_raw_mempool();
+    for (result, hop_sent) in results.iter_mut().zip(sent.iter_mut()) {
+        if !mempool.contains(&result.txid) {
+            let failure = TransferFailure::new(
+                STEP_MINING,
+                "NotBroadcast",
+                format!("transaction {} is not in the mempool", result.txid),
+            );
+            fail_sent(result, hop_sent, failure);
+        }
+    }
+    let t_mine = timestamp();
+    let mine_res = regtest::mine().map_err(mining_failure);
+    let elapsed = millis(timestamp() - t_mine);
+    progress(&format!("[{elapsed:6}]"));
+    shared_step(&mut results, &mut sent, mine_res, |t| {
+        t.mining = elapsed;
+        t.total += elapsed;
+    });
+    let t_wait = timestamp();
+    let wait_res = regtest::wait_electrum_sync().map_err(electrum_failure);
+    let elapsed = millis(timestamp() - t_wait);
+    progress(&format!(", electrum[{elapsed:6}]"));
+    shared_step(&mut results, &mut sent, wait_res, |t| {
+        t.electrum_wait = elapsed;
+        t.total += elapsed;
+    });
+    let mempool = regtest::get_raw_mempool();
+    for (result, hop_sent) in results.iter_mut().zip(sent.iter_mut()) {
+        if hop_sent.is_some() && mempool.contains(&result.txid) {
+            let failure = TransferFailure::new(
+                STEP_MINING,
+                "NotConfirmed",
+                format!("transaction {} is still in the mempool", result.txid),
+            );
+            fail_sent(result, hop_sent, failure);
+        }
+    }
+    end_progress();
+
+    for (i, hop) in hops.iter().enumerate().take(sent.len()) {
+        let Some(hop_sent) = sent[i].take() else {
+            continue;
+        };
+        let result = &mut results[i];
+        progress(&format!(
+            "  [{:2}] {}->{} > {:6} total",
+            i + 1,
+            hop.sender.fingerprint,
+            hop.recver.fingerprint,
+            result.times.total
+        ));
+        let settled =
+            asset_results(hop.sender, &result.txid, assets, &hop_sent.map).and_then(|assets| {
+                result.assets = assets;
+                check_settled(hop.sender, hop.recver, &hop_sent.map, retries)
+            });
+        match settled {
+            Ok(()) => {
+                progress(&format!(" assets: {}", assets_info(&result.assets)));
+                end_progress();
+                backup_wallets(result, [hop.sender, hop.recver], transfer_opts.backup_every);
+            }
+            Err(failure) => {
+                let total = result.times.total;
+                record_failure(result, failure, hop_sent.t_begin);
+                result.times.total = total;
+            }
+        }
+    }
+    results
+}
+
+/// Transfer carried out as part of a batch
+pub(crate) struct BatchTransfer<'a> {
+    pub(crate) sender: &'a WalletWrapper,
+    pub(crate) assets: Vec<RgbAsset>,
+    pub(crate) witness: bool,
+}
+
+/// Sent transfer of a batch, with its start time and recipient IDs
+struct BatchSent {
+    t_begin: Instant,
+    map: HashMap<String, String>,
+}
 
-    let ticker_size_id_strs = sent_asset_info
+/// Carry out transfers from different senders to a common receiver, confirming them all with a
+/// single block
+///
+/// All transfers are sent and ACKed (unless they are donations) before mining, so each receiver
+/// refresh processes all of them. The time of the receiver refreshes and of mining is shared, so
+/// it is reported for each transfer of the batch.
+pub(crate) fn send_assets_batch(
+    transfers: &[BatchTransfer],
+    recver: &WalletWrapper,
+    amount: u64,
+    test_mode: &TestMode,
+    transfer_opts: TransferOpts,
+) -> Vec<TransferResult> {
+    if let Some(failures) = manifest::replay_transfers(transfers.len()) {
+        return transfers
+            .iter()
+            .zip(failures)
+            .map(|(t, failure)| {
+                replayed_result(t.sender, recver, t.witness, transfer_opts, failure)
+            })
+            .collect();
+    }
+    let retries = retries(transfer_opts.on_error);
+    let mut results: Vec<TransferResult> = transfers
         .iter()
-        .map(|(ticker, cons_size, rcpt_id)| format!("{ticker},{cons_size},{rcpt_id}"))
-        .collect::<Vec<String>>();
+        .map(|t| new_result(t.sender, recver, t.witness, transfer_opts.donation))
+        .collect();
+    // sent transfers, None if the transfer failed
+    let mut sent: Vec<Option<BatchSent>> = Vec::with_capacity(transfers.len());
+
+    for (transfer, result) in transfers.iter().zip(results.iter_mut()) {
+        progress(&format!(
+            "  {}->{} ",
+            transfer.sender.fingerprint, recver.fingerprint
+        ));
+        let t_begin = timestamp();
+        let send_res = with_retries(retries, STEP_SEND, |_| {
+            transfer
+                .sender
+                .send(
+                    amount,
+                    recver,
+                    &transfer.assets,
+                    test_mode,
+                    transfer.witness,
+                    transfer_opts,
+                )
+                .map_err(|e| rgb_failure(STEP_SEND, &e))
+        });
+        result.times.send = millis(timestamp() - t_begin);
+        match send_res {
+            Ok((txid, map)) => {
+                result.txid = txid;
+                progress(&format!(
+                    "send[{:6}] {}",
+                    result.times.send,
+                    if transfer.witness { "w" } else { "b" }
+                ));
+                end_progress();
+                sent.push(Some(BatchSent { t_begin, map }));
+            }
+            Err(failure) => {
+                record_failure(result, failure, t_begin);
+                sent.push(None);
+            }
+        }
+    }
+
+    if !transfer_opts.donation {
+        progress("  refreshing: receiver");
+        let t_ref_recv_1 = timestamp();
+        let refresh_res = with_retries(retries, STEP_RECV_REFRESH_1, |_| {
+            recver
+                .refresh()
+                .map_err(|e| rgb_failure(STEP_RECV_REFRESH_1, &e))
+        });
+        let elapsed = millis(timestamp() - t_ref_recv_1);
+        progress(&format!("[{elapsed:6}]"));
+        shared_step(&mut results, &mut sent, refresh_res, |t| {
+            t.recv_refresh_1 = elapsed
+        });
+
+        progress(", senders");
+        for (i, transfer) in transfers.iter().enumerate() {
+            if sent[i].is_none() {
+                continue;
+            }
+            let t_ref_send_1 = timestamp();
+            let refresh_res = with_retries(retries, STEP_SEND_REFRESH_1, |_| {
+                transfer
+                    .sender
+                    .refresh()
+                    .map_err(|e| rgb_failure(STEP_SEND_REFRESH_1, &e))
+            });
+            results[i].times.send_refresh_1 = millis(timestamp() - t_ref_send_1);
+            progress(&format!("[{:6}]", results[i].times.send_refresh_1));
+            if let Err(failure) = refresh_res {
+                fail_sent(&mut results[i], &mut sent[i], failure);
+            }
+        }
+    }
+    progress(if transfer_opts.donation {
+        "  mining"
+    } else {
+        ", mining"
+    });
+    let mempool = regtest::get_raw_mempool();
+    for (result, sent) in results.iter_mut().zip(sent.iter_mut()) {
+        if sent.is_some() && !mempool.contains(&result.txid) {
+            let failure = TransferFailure::new(
+                STEP_MINING,
+                "NotBroadcast",
+                format!("transaction {} is not in the mempool", result.txid),
+            );
+            fail_sent(result, sent, failure);
+        }
+    }
+    if sent.iter().any(Option::is_some) {
+        let t_mine = timestamp();
+        let mine_res = regtest::mine().map_err(mining_failure);
+        let elapsed = millis(timestamp() - t_mine);
+        progress(&format!("[{elapsed:6}]"));
+        shared_step(&mut results, &mut sent, mine_res, |t| t.mining = elapsed);
+        shared_electrum_wait(&mut results, &mut sent);
+    }
+
+    progress(", receiver");
+    let t_ref_recv_2 = timestamp();
+    let refresh_res = with_retries(retries, STEP_RECV_REFRESH_2, |_| {
+        recver
+            .refresh()
+            .map_err(|e| rgb_failure(STEP_RECV_REFRESH_2, &e))
+    });
+    let elapsed = millis(timestamp() - t_ref_recv_2);
+    progress(&format!("[{elapsed:6}]"));
+    shared_step(&mut results, &mut sent, refresh_res, |t| {
+        t.recv_refresh_2 = elapsed
+    });
 
-    let consignment_str = ticker_size_id_strs.join(",");
+    progress(", senders");
+    for (i, transfer) in transfers.iter().enumerate() {
+        let Some(batch_sent) = &sent[i] else {
+            continue;
+        };
+        let t_ref_send_2 = timestamp();
+        let refresh_res = with_retries(retries, STEP_SEND_REFRESH_2, |_| {
+            transfer
+                .sender
+                .refresh()
+                .map_err(|e| rgb_failure(STEP_SEND_REFRESH_2, &e))
+        });
+        let t_end = timestamp();
+        results[i].times.send_refresh_2 = millis(t_end - t_ref_send_2);
+        results[i].times.total = millis(t_end - batch_sent.t_begin);
+        progress(&format!("[{:6}]", results[i].times.send_refresh_2));
+        if let Err(failure) = refresh_res {
+            fail_sent(&mut results[i], &mut sent[i], failure);
+        }
+    }
+    end_progress();
 
-    // check transfers have settled
-    sender.check_transfer(&map);
-    recver.check_transfer(&map);
+    for (i, transfer) in transfers.iter().enumerate() {
+        let Some(batch_sent) = sent[i].take() else {
+            continue;
+        };
+        let result = &mut results[i];
+        progress(&format!(
+            "  {}->{} > {:6} total",
+            transfer.sender.fingerprint, recver.fingerprint, result.times.total
+        ));
+        let settled = asset_results(
+            transfer.sender,
+            &result.txid,
+            &transfer.assets,
+            &batch_sent.map,
+        )
+        .and_then(|assets| {
+            result.assets = assets;
+            check_settled(transfer.sender, recver, &batch_sent.map, retries)
+        });
+        match settled {
+            Ok(()) => {
+                progress(&format!(" assets: {}", assets_info(&result.assets)));
+                end_progress();
+                backup_wallets(
+                    result,
+                    [transfer.sender, recver],
+                    transfer_opts.backup_every,
+                );
+            }
+            Err(failure) => {
+                let total = result.times.total;
+                record_failure(result, failure, batch_sent.t_begin);
+                result.times.total = total;
+            }
+        }
+    }
+    results
+}
 
-    format!(
-        "\"{}\",\"{}\",{},{},{},{},{},{},{},{},{}\n",
+/// Carry out a transfer from a sender to multiple receivers (each with its send mode) in a single
+/// transaction, returning a result for each receiver
+///
+/// The receiver refreshes are timed separately for each receiver, while the send, sender refreshes
+/// and mining are shared, so their time is reported for each receiver.
+pub(crate) fn send_assets_multi(
+    sender: &WalletWrapper,
+    recvers: &[(&WalletWrapper, bool)],
+    assets: &[RgbAsset],
+    amount: u64,
+    test_mode: &TestMode,
+    transfer_opts: TransferOpts,
+) -> Vec<TransferResult> {
+    if let Some(failures) = manifest::replay_transfers(recvers.len()) {
+        return recvers
+            .iter()
+            .zip(failures)
+            .map(|((recver, witness), failure)| {
+                replayed_result(sender, recver, *witness, transfer_opts, failure)
+            })
+            .collect();
+    }
+    let retries = retries(transfer_opts.on_error);
+    let mut results: Vec<TransferResult> = recvers
+        .iter()
+        .map(|(recver, witness)| new_result(sender, recver, *witness, transfer_opts.donation))
+        .collect();
+
+    progress(&format!(
+        "  {}->{} receivers ",
         sender.fingerprint,
-        recver.fingerprint,
-        if witness { "witness" } else { "blind" },
-        (t_send - t_begin).as_millis(),
-        (t_ref_recv_1 - t_send).as_millis(),
-        (t_ref_send_1 - t_ref_recv_1).as_millis(),
-        (t_ref_recv_2 - t_mine).as_millis(),
-        (t_end - t_ref_recv_2).as_millis(),
-        (t_end - t_begin).as_millis(),
-        txid,
-        consignment_str,
-    )
+        recvers.len()
+    ));
+    let t_begin = timestamp();
+    let send_res = with_retries(retries, STEP_SEND, |_| {
+        sender
+            .send_to(amount, recvers, assets, test_mode, transfer_opts)
+            .map_err(|e| rgb_failure(STEP_SEND, &e))
+    });
+    let send_time = millis(timestamp() - t_begin);
+    for result in results.iter_mut() {
+        result.times.send = send_time;
+    }
+    let maps = match send_res {
+        Ok((txid, maps)) => {
+            for result in results.iter_mut() {
+                result.txid = txid.clone();
+            }
+            maps
+        }
+        Err(failure) => {
+            // the failed send is shared by all receivers
+            record_failure(&mut results[0], failure, t_begin);
+            let failed = results[0].clone();
+            for result in results.iter_mut().skip(1) {
+                result.times = failed.times.clone();
+                result.failure = failed.failure.clone();
+            }
+            return results;
+        }
+    };
+    progress(&format!("send[{send_time:6}] >"));
+    // sent transfers, None if the transfer to the receiver failed
+    let mut sent: Vec<Option<BatchSent>> = maps
+        .into_iter()
+        .map(|map| Some(BatchSent { t_begin, map }))
+        .collect();
+
+    let receiver_refreshes =
+        |results: &mut [TransferResult],
+         sent: &mut [Option<BatchSent>],
+         step: &str,
+         record_time: fn(&mut StepTimes) -> &mut u64| {
+            for (i, (recver, _)) in recvers.iter().enumerate() {
+                if sent[i].is_none() {
+                    continue;
+                }
+                let t_ref = timestamp();
+                let refresh_res = with_retries(retries, step, |_| {
+                    recver.refresh().map_err(|e| rgb_failure(step, &e))
+                });
+                let elapsed = millis(timestamp() - t_ref);
+                *record_time(&mut results[i].times) = elapsed;
+                progress(&format!("[{elapsed:6}]"));
+                if let Err(failure) = refresh_res {
+                    fail_sent(&mut results[i], &mut sent[i], failure);
+                }
+            }
+        };
+
+    if !transfer_opts.donation {
+        progress(" refreshing: receivers");
+        receiver_refreshes(&mut results, &mut sent, STEP_RECV_REFRESH_1, |t| {
+            &mut t.recv_refresh_1
+        });
+        progress(", sender");
+        let t_ref_send_1 = timestamp();
+        let refresh_res = with_retries(retries, STEP_SEND_REFRESH_1, |_| {
+            sender
+                .refresh()
+                .map_err(|e| rgb_failure(STEP_SEND_REFRESH_1, &e))
+        });
+        let elapsed = millis(timestamp() - t_ref_send_1);
+        progress(&format!("[{elapsed:6}]"));
+        shared_step(&mut results, &mut sent, refresh_res, |t| {
+            t.send_refresh_1 = elapsed
+        });
+
+        progress(",");
+    }
+    progress(" mining");
+    if sent.iter().any(Option::is_some) {
+        let txid = results[0].txid.clone();
+        if regtest::get_raw_mempool().contains(&txid) {
+            let t_mine = timestamp();
+            let mine_res = regtest::mine().map_err(mining_failure);
+            let elapsed = millis(timestamp() - t_mine);
+            progress(&format!("[{elapsed:6}]"));
+            shared_step(&mut results, &mut sent, mine_res, |t| t.mining = elapsed);
+            shared_electrum_wait(&mut results, &mut sent);
+        } else {
+            let failure = TransferFailure::new(
+                STEP_MINING,
+                "NotBroadcast",
+                format!("transaction {txid} is not in the mempool"),
+            );
+            shared_step(&mut results, &mut sent, Err::<(), _>(failure), |_| {});
+        }
+    }
+
+    progress(", receivers");
+    receiver_refreshes(&mut results, &mut sent, STEP_RECV_REFRESH_2, |t| {
+        &mut t.recv_refresh_2
+    });
+    progress(", sender");
+    let t_ref_send_2 = timestamp();
+    let refresh_res = with_retries(retries, STEP_SEND_REFRESH_2, |_| {
+        sender
+            .refresh()
+            .map_err(|e| rgb_failure(STEP_SEND_REFRESH_2, &e))
+    });
+    let t_end = timestamp();
+    let elapsed = millis(t_end - t_ref_send_2);
+    progress(&format!("[{elapsed:6}]"));
+    shared_step(&mut results, &mut sent, refresh_res, |t| {
+        t.send_refresh_2 = elapsed;
+        t.total = millis(t_end - t_begin);
+    });
+    end_progress();
+
+    for (i, (recver, witness)) in recvers.iter().enumerate() {
+        let Some(batch_sent) = sent[i].take() else {
+            continue;
+        };
+        let result = &mut results[i];
+        progress(&format!(
+            "  {}->{} > {:6} total {}",
+            sender.fingerprint,
+            recver.fingerprint,
+            result.times.total,
+            if *witness { "w" } else { "b" }
+        ));
+        let settled =
+            asset_results(sender, &result.txid, assets, &batch_sent.map).and_then(|assets| {
+                result.assets = assets;
+                check_settled(sender, recver, &batch_sent.map, retries)
+            });
+        match settled {
+            Ok(()) => {
+                progress(&format!(" assets: {}", assets_info(&result.assets)));
+                end_progress();
+                backup_wallets(result, [sender, recver], transfer_opts.backup_every);
+            }
+            Err(failure) => {
+                let total = result.times.total;
+                record_failure(result, failure, batch_sent.t_begin);
+                result.times.total = total;
+            }
+        }
+    }
+    results
+}
+
+/// Record the outcome of a step shared by all the sent transfers of a batch
+fn shared_step<T>(
+    results: &mut [TransferResult],
+    sent: &mut [Option<BatchSent>],
+    res: Result<T, TransferFailure>,
+    record_time: impl Fn(&mut StepTimes),
+) {
+    for (result, sent) in results.iter_mut().zip(sent.iter_mut()) {
+        if sent.is_none() {
+            continue;
+        }
+        record_time(&mut result.times);
+        if let Err(failure) = &res {
+            fail_sent(result, sent, failure.clone());
+        }
+    }
+}
+
+/// Wait for the electrum server to index the block confirming a batch
+fn shared_electrum_wait(results: &mut [TransferResult], sent: &mut [Option<BatchSent>]) {
+    let t_wait = timestamp();
+    let wait_res = regtest::wait_electrum_sync().map_err(electrum_failure);
+    let elapsed = millis(timestamp() - t_wait);
+    progress(&format!(", electrum[{elapsed:6}]"));
+    shared_step(results, sent, wait_res, |t| t.electrum_wait = elapsed);
+}
+
+/// Record the failure of a sent transfer of a batch, which is then excluded from later steps
+fn fail_sent(result: &mut TransferResult, sent: &mut Option<BatchSent>, failure: TransferFailure) {
+    if let Some(batch_sent) = sent.take() {
+        result.times.total = millis(timestamp() - batch_sent.t_begin);
+    }
+    result.failure = Some(failure);
 }
 
 fn timestamp() -> Instant {
     Instant::now()
 }
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis() as u64
+}
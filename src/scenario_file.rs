@@ -0,0 +1,570 @@
+use rand::prelude::*;
+use serde::Deserialize;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+
+use crate::opts::{Opts, Schema};
+use crate::report::Report;
+use crate::rgb;
+use crate::rgb::{RgbAsset, TestMode, TransferOpts, WalletWrapper};
+use crate::scenarios::{get_scenario_opts, get_wallet, transfer_rng, ScenarioOpts};
+
+/// Scenario described by a TOML or YAML file
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct ScenarioFile {
+    /// Fund wallets and create UTXOs when operations fail for lack of them
+    #[serde(default)]
+    pub(crate) handle_utxo_errors: bool,
+    wallets: Vec<WalletSpec>,
+    #[serde(default)]
+    issuances: Vec<IssuanceSpec>,
+    steps: Vec<Step>,
+}
+
+/// A wallet or, when `count` is higher than 1, a group of wallets named `<name>0`, `<name>1`...
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct WalletSpec {
+    name: String,
+    #[serde(default = "default_one")]
+    count: u8,
+    /// Number of allocation UTXOs (defaults to `--allocation-utxos`)
+    utxos: Option<u8>,
+    /// Size of allocation UTXOs (defaults to `--utxo-size`)
+    utxo_size: Option<u32>,
+    /// Multiplier applied to the allocation UTXO size
+    #[serde(default = "default_one")]
+    utxo_size_factor: u32,
+    max_allocations_per_utxo: Option<u32>,
+}
+
+/// An asset or, when `count` is higher than 1, a group of assets named `<name>0`, `<name>1`...
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct IssuanceSpec {
+    name: String,
+    #[serde(default = "default_one")]
+    count: u8,
+    /// Issuing wallet, if a group is given a random wallet is picked for each asset
+    wallet: String,
+    /// Number of allocations of `--send-amount` each
+    #[serde(default = "default_one")]
+    allocations: u8,
+    /// Explicit allocation amounts, overriding `allocations`
+    amounts: Option<Vec<u64>>,
+    /// Asset schema (defaults to `--schema`)
+    schema: Option<Schema>,
+}
+
+impl IssuanceSpec {
+    fn allocations(&self) -> usize {
+        self.amounts
+            .as_ref()
+            .map_or(self.allocations as usize, |a| a.len())
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+enum SendMode {
+    Blind,
+    Witness,
+    Random,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy)]
+#[serde(rename_all = "kebab-case")]
+enum RandomSender {
+    /// Start from the first wallet of the group, then send from the last receiver
+    LastReceiver,
+    /// Pick a random wallet with spendable balance
+    Spendable,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "kebab-case", deny_unknown_fields)]
+enum Step {
+    /// Send assets between two wallets
+    Send {
+        from: String,
+        to: String,
+        assets: Vec<String>,
+        /// Send amount (defaults to `--send-amount`)
+        amount: Option<u64>,
+        /// Multiplier applied to the send amount
+        #[serde(default = "default_one")]
+        amount_factor: u64,
+        mode: Option<SendMode>,
+        /// Send as a donation (defaults to `--donation`)
+        donation: Option<bool>,
+    },
+    /// Repeat steps `times` times
+    Loop { times: u16, steps: Vec<Step> },
+    /// Send between randomly-selected wallets of a group `times` times
+    RandomSend {
+        wallets: String,
+        sender: RandomSender,
+        /// Assets to choose from (defaults to any spendable asset)
+        assets: Option<Vec<String>>,
+        /// Send amount (defaults to a random fraction of the spendable balance)
+        amount: Option<u64>,
+        #[serde(default = "default_one")]
+        times: u16,
+        mode: Option<SendMode>,
+        /// Send as donations (defaults to `--donation`)
+        donation: Option<bool>,
+    },
+    /// Show wallet unspents and related RGB allocations if `--verbose` is set
+    Verbose {
+        wallet: String,
+        title: Option<String>,
+    },
+    /// Print a message
+    Print { message: String },
+}
+
+fn default_one<T: From<u8>>() -> T {
+    T::from(1)
+}
+
+/// Load and validate a scenario file, the format is selected based on the file extension
+///
+/// The given schema is the one used for issuances not setting one.
+pub(crate) fn load(path: &Path, default_schema: Schema) -> Result<ScenarioFile, String> {
+    let content = fs::read_to_string(path)
+        .map_err(|e| format!("cannot read scenario file '{}': {e}", path.display()))?;
+    let scenario: ScenarioFile = match path.extension().and_then(|e| e.to_str()) {
+        Some("toml") => toml::from_str(&content).map_err(|e| e.to_string()),
+        Some("yaml" | "yml") => serde_yaml::from_str(&content).map_err(|e| e.to_string()),
+        _ => Err("unsupported extension (expected .toml, .yaml or .yml)".to_string()),
+    }
+    .map_err(|e| format!("invalid scenario file '{}': {e}", path.display()))?;
+    scenario
+        .validate(default_schema)
+        .map_err(|e| format!("invalid scenario file '{}': {e}", path.display()))?;
+    Ok(scenario)
+}
+
+fn expand_names(name: &str, count: u8) -> Vec<String> {
+    if count == 1 {
+        vec![name.to_string()]
+    } else {
+        (0..count).map(|i| format!("{name}{i}")).collect()
+    }
+}
+
+impl ScenarioFile {
+    fn validate(&self, default_schema: Schema) -> Result<(), String> {
+        // wallet (or wallet group) name -> number of wallets
+        let mut wallets = HashMap::new();
+        for spec in &self.wallets {
+            if spec.count == 0 {
+                return Err(format!("wallet '{}' has count 0", spec.name));
+            }
+            for name in expand_names(&spec.name, spec.count) {
+                if wallets.insert(name.clone(), 1).is_some() {
+                    return Err(format!("duplicate wallet '{name}'"));
+                }
+            }
+            // the name of a single wallet has just been inserted
+            if spec.count > 1 && wallets.insert(spec.name.clone(), spec.count).is_some() {
+                return Err(format!("duplicate wallet '{}'", spec.name));
+            }
+        }
+        let mut assets = HashSet::new();
+        for spec in &self.issuances {
+            if spec.count == 0 {
+                return Err(format!("issuance '{}' has count 0", spec.name));
+            }
+            if !wallets.contains_key(&spec.wallet) {
+                return Err(format!("unknown wallet '{}'", spec.wallet));
+            }
+            if spec.schema.unwrap_or(default_schema) == Schema::Uda && spec.allocations() != 1 {
+                return Err(format!(
+                    "UDA issuance '{}' must have a single allocation",
+                    spec.name
+                ));
+            }
+            for name in expand_names(&spec.name, spec.count) {
+                if !assets.insert(name.clone()) {
+                    return Err(format!("duplicate asset '{name}'"));
+                }
+            }
+            if spec.count > 1 && !assets.insert(spec.name.clone()) {
+                return Err(format!("duplicate asset '{}'", spec.name));
+            }
+        }
+        check_steps(&self.steps, &wallets, &assets)
+    }
+}
+
+fn check_steps(
+    steps: &[Step],
+    wallets: &HashMap<String, u8>,
+    assets: &HashSet<String>,
+) -> Result<(), String> {
+    let check_wallet = |name: &String| {
+        if wallets.contains_key(name) {
+            Ok(())
+        } else {
+            Err(format!("unknown wallet '{name}'"))
+        }
+    };
+    let check_assets = |names: &[String]| match names.iter().find(|n| !assets.contains(*n)) {
+        Some(name) => Err(format!("unknown asset '{name}'")),
+        None => Ok(()),
+    };
+    for step in steps {
+        match step {
+            Step::Send {
+                from, to, assets, ..
+            } => {
+                check_wallet(from)?;
+                check_wallet(to)?;
+                if assets.is_empty() {
+                    return Err(format!("send from '{from}' to '{to}' has no assets"));
+                }
+                check_assets(assets)?;
+            }
+            Step::Loop { steps, .. } => check_steps(steps, wallets, assets)?,
+            Step::RandomSend {
+                wallets: group,
+                assets,
+                ..
+            } => {
+                if wallets.get(group).is_none_or(|count| *count < 2) {
+                    return Err(format!(
+                        "random send needs a group of at least 2 wallets, got '{group}'"
+                    ));
+                }
+                check_assets(assets.as_deref().unwrap_or_default())?;
+            }
+            Step::Verbose { wallet, .. } => check_wallet(wallet)?,
+            Step::Print { .. } => {}
+        }
+    }
+    Ok(())
+}
+
+/// Runtime state of a scenario file execution
+struct Runner<'a> {
+    opts: ScenarioOpts,
+    test_mode: TestMode,
+    report: &'a mut Report,
+    wallets: Vec<WalletWrapper>,
+    /// wallet (or wallet group) name -> wallet indexes
+    wallet_refs: HashMap<String, Vec<usize>>,
+    /// asset (or asset group) name -> asset list
+    asset_refs: HashMap<String, Vec<RgbAsset>>,
+    /// number of assets issued so far
+    issued: usize,
+    /// number of random transfers drawn so far
+    random_transfers: u64,
+    rng: StdRng,
+}
+
+pub(crate) fn run(opts: Opts, report: &mut Report, scenario: ScenarioFile) {
+    let scenario_opts = get_scenario_opts(opts);
+    let test_mode = if scenario.handle_utxo_errors {
+        TestMode::HandleUtxoErrors {
+            utxos: scenario_opts.utxo_num,
+            utxo_size: scenario_opts.utxo_size,
+        }
+    } else {
+        TestMode::NoErrorHandling
+    };
+    let mut runner = Runner {
+        rng: StdRng::seed_from_u64(scenario_opts.seed),
+        opts: scenario_opts,
+        test_mode,
+        report,
+        wallets: vec![],
+        wallet_refs: HashMap::new(),
+        asset_refs: HashMap::new(),
+        issued: 0,
+        random_transfers: 0,
+    };
+
+    println!("\nsetup wallets");
+    for spec in &scenario.wallets {
+        runner.setup_wallets(spec);
+    }
+
+    print!("\nissue assets");
+    std::io::stdout().flush().unwrap();
+    for spec in &scenario.issuances {
+        runner.issue_assets(spec);
+    }
+    println!();
+
+    runner.run_steps(&scenario.steps);
+}
+
+/// Whether to send via witness, drawing it for the random send mode
+fn witness(mode: Option<SendMode>, default: SendMode, rng: &mut StdRng) -> bool {
+    match mode.unwrap_or(default) {
+        SendMode::Blind => false,
+        SendMode::Witness => true,
+        SendMode::Random => rng.gen_bool(0.5),
+    }
+}
+
+impl Runner<'_> {
+    fn setup_wallets(&mut self, spec: &WalletSpec) {
+        let utxos = spec.utxos.unwrap_or(self.opts.utxo_num);
+        let utxo_size = spec.utxo_size.unwrap_or(self.opts.utxo_size) * spec.utxo_size_factor;
+        let mut indexes = Vec::with_capacity(spec.count as usize);
+        for name in expand_names(&spec.name, spec.count) {
+            let index = self.wallets.len();
+            let wallet = get_wallet(
+                &self.opts.data_dir,
+                index as u8,
+                utxos,
+                utxo_size,
+                spec.max_allocations_per_utxo,
+            );
+            self.wallets.push(wallet);
+            self.wallet_refs.insert(name, vec![index]);
+            indexes.push(index);
+        }
+        self.wallet_refs.insert(spec.name.clone(), indexes);
+    }
+
+    fn issue_assets(&mut self, spec: &IssuanceSpec) {
+        let amounts = spec
+            .amounts
+            .clone()
+            .unwrap_or_else(|| vec![self.opts.send_amount; spec.allocations as usize]);
+        let mut group = Vec::with_capacity(spec.count as usize);
+        for name in expand_names(&spec.name, spec.count) {
+            let wallet_index = *self.wallet_refs[&spec.wallet]
+                .choose(&mut self.rng)
+                .expect("wallet reference should not be empty");
+            let schema = spec
+                .schema
+                .unwrap_or(self.opts.schema)
+                .for_issuance(self.issued, amounts.len());
+            let asset = self.wallets[wallet_index].issue(
+                schema,
+                amounts.clone(),
+                self.opts.media_size,
+                &self.test_mode,
+            );
+            self.issued += 1;
+            print!(" {}:{}", name, asset.ticker);
+            std::io::stdout().flush().unwrap();
+            self.asset_refs.insert(name, vec![asset.clone()]);
+            group.push(asset);
+        }
+        self.asset_refs.insert(spec.name.clone(), group);
+    }
+
+    fn wallet(&self, name: &str) -> usize {
+        self.wallet_refs[name][0]
+    }
+
+    fn assets(&self, names: &[String]) -> Vec<RgbAsset> {
+        names
+            .iter()
+            .flat_map(|n| self.asset_refs[n].iter().cloned())
+            .collect()
+    }
+
+    fn transfer_opts(&self, donation: Option<bool>) -> TransferOpts {
+        TransferOpts {
+            donation: donation.unwrap_or(self.opts.transfer_opts.donation),
+            ..self.opts.transfer_opts
+        }
+    }
+
+    fn send(
+        &mut self,
+        sender: usize,
+        recver: usize,
+        assets: &[RgbAsset],
+        amount: u64,
+        witness: bool,
+        transfer_opts: TransferOpts,
+    ) -> bool {
+        let result = rgb::send_assets(
+            &self.wallets[sender],
+            &self.wallets[recver],
+            assets,
+            amount,
+            &self.test_mode,
+            witness,
+            transfer_opts,
+        );
+        let succeeded = result.succeeded();
+        self.report.write(result);
+        succeeded
+    }
+
+    fn run_steps(&mut self, steps: &[Step]) {
+        for step in steps {
+            match step {
+                Step::Send {
+                    from,
+                    to,
+                    assets,
+                    amount,
+                    amount_factor,
+                    mode,
+                    donation,
+                } => {
+                    let default_mode = if self.opts.witness {
+                        SendMode::Witness
+                    } else {
+                        SendMode::Blind
+                    };
+                    let witness = witness(*mode, default_mode, &mut self.rng);
+                    let amount = amount.unwrap_or(self.opts.send_amount) * amount_factor;
+                    let assets = self.assets(assets);
+                    let transfer_opts = self.transfer_opts(*donation);
+                    self.send(
+                        self.wallet(from),
+                        self.wallet(to),
+                        &assets,
+                        amount,
+                        witness,
+                        transfer_opts,
+                    );
+                }
+                Step::Loop { times, steps } => {
+                    for i in 1..=*times {
+                        println!("loop {i}/{times}");
+                        self.run_steps(steps);
+                    }
+                }
+                Step::RandomSend {
+                    wallets,
+                    sender,
+                    assets,
+                    amount,
+                    times,
+                    mode,
+                    donation,
+                } => {
+                    let transfer_opts = self.transfer_opts(*donation);
+                    self.random_send(
+                        wallets,
+                        *sender,
+                        assets.as_deref(),
+                        *amount,
+                        *times,
+                        *mode,
+                        transfer_opts,
+                    )
+                }
+                Step::Verbose { wallet, title } => {
+                    if self.opts.verbose {
+                        println!(
+                            "\n{}",
+                            title
+                                .as_deref()
+                                .unwrap_or("wallet unspents and related RGB allocations:")
+                        );
+                        self.wallets[self.wallet(wallet)].show_unspents_with_allocations();
+                    }
+                }
+                Step::Print { message } => println!("\n{message}"),
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn random_send(
+        &mut self,
+        group: &str,
+        sender_mode: RandomSender,
+        asset_names: Option<&[String]>,
+        amount: Option<u64>,
+        times: u16,
+        mode: Option<SendMode>,
+        transfer_opts: TransferOpts,
+    ) {
+        let group = self.wallet_refs[group].clone();
+        let allowed_assets = asset_names.map(|names| self.assets(names));
+        let default_mode = if self.opts.witness {
+            SendMode::Random
+        } else {
+            SendMode::Blind
+        };
+        let mut last_receiver = group[0];
+        let len = times.to_string().len();
+        for i in 1..=times {
+            let mut rng = transfer_rng(self.opts.seed, self.random_transfers);
+            self.random_transfers += 1;
+            let (sender, recver, asset, tx_amount) = match sender_mode {
+                RandomSender::LastReceiver => {
+                    let sender = last_receiver;
+                    let recver = *group
+                        .iter()
+                        .filter(|w| **w != sender)
+                        .choose(&mut rng)
+                        .expect("group should have at least 2 wallets");
+                    let assets = allowed_assets
+                        .clone()
+                        .unwrap_or_else(|| self.spendable_assets(sender));
+                    let asset = assets
+                        .choose(&mut rng)
+                        .expect("asset should be available")
+                        .clone();
+                    (
+                        sender,
+                        recver,
+                        asset,
+                        amount.unwrap_or(self.opts.send_amount),
+                    )
+                }
+                RandomSender::Spendable => {
+                    let mut wallet_indexes = group.clone();
+                    wallet_indexes.shuffle(&mut rng);
+                    let sender_pos = wallet_indexes
+                        .iter()
+                        .position(|w| !self.spendable(*w, allowed_assets.as_deref()).is_empty())
+                        .expect("at least one wallet must have spendable assets");
+                    let sender = wallet_indexes.remove(sender_pos);
+                    let recver = wallet_indexes.pop().expect("wallet should be available");
+                    let spendable = self.spendable(sender, allowed_assets.as_deref());
+                    let (asset, balance) = spendable
+                        .choose(&mut rng)
+                        .expect("spendable asset should be available")
+                        .clone();
+                    let tx_amount = amount.unwrap_or_else(|| {
+                        let p = rng.gen_range(1..=10);
+                        cmp::max(1, balance / p)
+                    });
+                    (sender, recver, asset, tx_amount)
+                }
+            };
+            print!("[{i:len$}/{times}] ");
+            std::io::stdout().flush().unwrap();
+            let witness = witness(mode, default_mode, &mut rng);
+            // on failure the next transfer is sent again from the same wallet
+            if self.send(sender, recver, &[asset], tx_amount, witness, transfer_opts) {
+                last_receiver = recver;
+            }
+        }
+    }
+
+    fn spendable_assets(&self, wallet: usize) -> Vec<RgbAsset> {
+        self.spendable(wallet, None)
+            .into_iter()
+            .map(|(asset, _)| asset)
+            .collect()
+    }
+
+    /// Assets with spendable balance, optionally restricted to the given ones
+    fn spendable(&self, wallet: usize, allowed: Option<&[RgbAsset]>) -> Vec<(RgbAsset, u64)> {
+        self.wallets[wallet]
+            .spendable_assets()
+            .into_iter()
+            .filter(|(a, _)| allowed.is_none_or(|l| l.iter().any(|b| b.asset_id == a.asset_id)))
+            .collect()
+    }
+}
@@ -1,44 +1,86 @@
 use rand::prelude::*;
 use std::cmp;
-use std::fs;
 use std::io::Write;
+use std::sync::Mutex;
 
-use crate::constants::{DEFAULT_MAX_ALLOCATIONS_PER_UTXO, ELECTRUM_URL, FEE_AMT};
-use crate::opts::Opts;
+use crate::constants::{DEFAULT_MAX_ALLOCATIONS_PER_UTXO, FEE_AMT};
+use crate::manifest::{self, ManifestWallet};
+use crate::opts::{Opts, Schema};
+use crate::regtest;
+use crate::report::{Report, TransferResult};
 use crate::rgb;
-use crate::rgb::{TestMode, WalletWrapper};
-use rgb_lib::wallet::{AssetNIA, DatabaseType, Wallet, WalletData};
+use crate::rgb::{BatchTransfer, ChainHop, RgbAsset, TestMode, TransferOpts, WalletWrapper};
+use crate::soak::Budget;
+use crate::summary;
+use rgb_lib::wallet::{DatabaseType, Wallet, WalletData};
 use rgb_lib::{generate_keys, BitcoinNetwork};
 
-struct ScenarioOpts {
-    data_dir: String,
-    output: String,
-    send_amount: u64,
-    utxo_num: u8,
-    utxo_size: u32,
-    verbose: bool,
-    witness: bool,
+pub(crate) struct ScenarioOpts {
+    pub(crate) data_dir: String,
+    pub(crate) send_amount: u64,
+    pub(crate) utxo_num: u8,
+    pub(crate) utxo_size: u32,
+    pub(crate) verbose: bool,
+    pub(crate) witness: bool,
+    pub(crate) transfer_opts: TransferOpts,
+    pub(crate) schema: Schema,
+    pub(crate) media_size: u32,
+    pub(crate) concurrency: u8,
+    pub(crate) seed: u64,
 }
 
-fn get_scenario_opts(opts: Opts) -> ScenarioOpts {
+pub(crate) fn get_scenario_opts(opts: Opts) -> ScenarioOpts {
     ScenarioOpts {
         data_dir: opts.data_dir.to_str().unwrap().to_string(),
-        output: opts.output.to_str().unwrap().to_string(),
         send_amount: opts.send_amount,
         utxo_num: opts.allocation_utxos,
         utxo_size: opts.utxo_size,
         verbose: opts.verbose,
         witness: opts.witness,
+        transfer_opts: TransferOpts {
+            on_error: opts.on_error,
+            donation: opts.donation,
+            min_confirmations: opts.min_confirmations,
+            backup_every: opts.backup_every,
+        },
+        schema: opts.schema,
+        media_size: opts.media_size,
+        concurrency: opts.concurrency,
+        seed: opts.seed.expect("seed should have been set"),
     }
 }
 
-fn get_wallet(
+/// RNG of the given random transfer, so that its choices only depend on the seed and not on the
+/// draws of the previous transfers, which vary with wallet balances when a run is resumed
+pub(crate) fn transfer_rng(seed: u64, transfer: u64) -> StdRng {
+    StdRng::seed_from_u64(seed ^ transfer.wrapping_mul(0x9e37_79b9_7f4a_7c15))
+}
+
+/// Wallet set up during the run, with the data needed to reopen or restore it
+#[derive(Clone, Debug)]
+pub(crate) struct WalletInfo {
+    pub(crate) fingerprint: String,
+    pub(crate) wallet_index: u8,
+    pub(crate) wallet_data: WalletData,
+}
+
+/// Wallets set up during the run, in setup order
+static WALLETS: Mutex<Vec<WalletInfo>> = Mutex::new(Vec::new());
+
+pub(crate) fn created_wallets() -> Vec<WalletInfo> {
+    WALLETS.lock().unwrap().clone()
+}
+
+pub(crate) fn get_wallet(
     data_dir: &str,
     wallet_index: u8,
     utxo_num: u8,
     utxo_size: u32,
     max_allocations_per_utxo: Option<u32>,
 ) -> WalletWrapper {
+    if let Some(wallet) = manifest::replay_wallet(wallet_index) {
+        return reopen_wallet(data_dir, wallet);
+    }
     print!("setting up wallet {wallet_index}");
     let keys = generate_keys(BitcoinNetwork::Regtest);
     let fingerprint = keys.xpub_fingerprint;
@@ -48,99 +90,198 @@ fn get_wallet(
         bitcoin_network: BitcoinNetwork::Regtest,
         database_type: DatabaseType::Sqlite,
         pubkey: keys.xpub,
-        mnemonic: Some(keys.mnemonic),
+        mnemonic: Some(keys.mnemonic.clone()),
         max_allocations_per_utxo: max_allocations_per_utxo
             .unwrap_or(DEFAULT_MAX_ALLOCATIONS_PER_UTXO),
         vanilla_keychain: None,
     };
+    WALLETS.lock().unwrap().push(WalletInfo {
+        fingerprint: fingerprint.clone(),
+        wallet_index,
+        wallet_data: wallet_data.clone(),
+    });
+    let manifest_wallet = ManifestWallet {
+        wallet_index,
+        fingerprint: fingerprint.clone(),
+        mnemonic: keys.mnemonic,
+        pubkey: wallet_data.pubkey.clone(),
+        max_allocations_per_utxo: wallet_data.max_allocations_per_utxo,
+        transfers: 0,
+    };
     let mut wallet = Wallet::new(wallet_data).unwrap();
-    let online = wallet.go_online(true, ELECTRUM_URL.to_string()).unwrap();
+    let online = wallet
+        .go_online(true, regtest::electrum_url().to_string())
+        .unwrap();
     let wallet_wrapper = WalletWrapper::new(wallet, online, fingerprint, wallet_index);
 
     let fund_amount = (utxo_num as u32 * utxo_size) + (utxo_num as u32 * FEE_AMT);
     wallet_wrapper.fund(fund_amount);
     wallet_wrapper.create_utxos(utxo_num, utxo_size, true);
+    // recorded once funded, so a resumed run never reopens an unfunded wallet
+    manifest::record_wallet(manifest_wallet);
 
     wallet_wrapper
 }
 
-fn write_report_header(report_file: &mut fs::File) {
-    let report_header = concat!(
-        "sender",
-        ",receiver",
-        ",send mode",
-        ",send,recv refresh 1,send refresh 1,recv refresh 2,send refresh 2",
-        ",total time",
-        ",txid",
-        ",ticker,consignment size,recipient id\n",
+/// Reopen a wallet of an interrupted run, which has already been funded
+fn reopen_wallet(data_dir: &str, wallet: ManifestWallet) -> WalletWrapper {
+    println!(
+        "reopening wallet {}, fingerprint: {}",
+        wallet.wallet_index, wallet.fingerprint
     );
-    write_report_line(report_file, report_header);
-}
-
-fn write_report_line(report_file: &mut fs::File, line: &str) {
-    report_file
-        .write_all(line.as_bytes())
-        .expect("line should have been written");
+    let wallet_data = WalletData {
+        data_dir: data_dir.to_string(),
+        bitcoin_network: BitcoinNetwork::Regtest,
+        database_type: DatabaseType::Sqlite,
+        pubkey: wallet.pubkey,
+        mnemonic: Some(wallet.mnemonic),
+        max_allocations_per_utxo: wallet.max_allocations_per_utxo,
+        vanilla_keychain: None,
+    };
+    WALLETS.lock().unwrap().push(WalletInfo {
+        fingerprint: wallet.fingerprint.clone(),
+        wallet_index: wallet.wallet_index,
+        wallet_data: wallet_data.clone(),
+    });
+    let mut rgb_wallet = Wallet::new(wallet_data).unwrap();
+    let online = rgb_wallet
+        .go_online(false, regtest::electrum_url().to_string())
+        .unwrap();
+    let wallet_wrapper =
+        WalletWrapper::new(rgb_wallet, online, wallet.fingerprint, wallet.wallet_index);
+    // continue numbering backups after the ones of the interrupted run
+    wallet_wrapper.resume_transfers(wallet.transfers);
+    // the transfer in progress when the run was interrupted is carried out again, so confirm or
+    // fail it first, or it would keep holding its allocations
+    regtest::mine().expect("failed to mine");
+    regtest::wait_electrum_sync().expect("electrum server should have synced");
+    wallet_wrapper.recover_transfers();
+    wallet_wrapper
 }
 
-pub(crate) fn send_loop(opts: Opts, loops: u16) {
+pub(crate) fn send_loop(opts: Opts, report: &mut Report, loops: u16) {
+    let budget = Budget::new(loops, opts.duration);
     let ScenarioOpts {
         data_dir,
-        output,
         send_amount,
         utxo_num: utxos,
         utxo_size,
         verbose: _,
         witness,
+        transfer_opts,
+        schema,
+        media_size,
+        concurrency,
+        seed: _,
     } = get_scenario_opts(opts);
-    let mut report_file = fs::File::create(output).expect("file should have been created");
-    write_report_header(&mut report_file);
+    // a timed run can outlast the funds and UTXOs set up for `loops` loops
+    let test_mode = &if budget.is_timed() {
+        TestMode::HandleUtxoErrors { utxos, utxo_size }
+    } else {
+        TestMode::NoErrorHandling
+    };
 
-    let mut wallet_1 = get_wallet(&data_dir, 1, utxos, utxo_size * loops as u32, None);
-    let wallet_2 = get_wallet(&data_dir, 2, utxos, utxo_size * loops as u32, None);
+    // one pair of wallets per worker
+    let mut pairs = Vec::with_capacity(concurrency as usize);
+    for worker in 0..concurrency {
+        let mut wallet_1 = get_wallet(
+            &data_dir,
+            worker * 2 + 1,
+            utxos,
+            utxo_size * loops as u32,
+            None,
+        );
+        let wallet_2 = get_wallet(
+            &data_dir,
+            worker * 2 + 2,
+            utxos,
+            utxo_size * loops as u32,
+            None,
+        );
 
-    // RGB asset issuance
-    println!("issuing asset");
-    let asset = wallet_1.issue_nia(vec![send_amount], &TestMode::NoErrorHandling);
+        // RGB asset issuance
+        println!("issuing asset");
+        let asset = wallet_1.issue(
+            schema.for_issuance(worker as usize, 1),
+            vec![send_amount],
+            media_size,
+            &TestMode::NoErrorHandling,
+        );
+        pairs.push((wallet_1, wallet_2, vec![asset]));
+    }
 
     // RGB asset send loop
     println!("\nsend loops");
-    let assets = vec![(asset.asset_id, asset.ticker)];
-    for i in 1..=loops {
-        println!("loop {i}/{loops}");
-        let result = rgb::send_assets(
-            &wallet_1,
-            &wallet_2,
-            &assets,
-            send_amount,
-            &TestMode::NoErrorHandling,
-            witness,
-        );
-        write_report_line(&mut report_file, &result);
-        let result = rgb::send_assets(
-            &wallet_2,
-            &wallet_1,
-            &assets,
-            send_amount,
-            &TestMode::NoErrorHandling,
-            witness,
-        );
-        write_report_line(&mut report_file, &result);
+    run_workers(
+        report,
+        &pairs,
+        |worker, (wallet_1, wallet_2, assets), write| {
+            for i in budget.iter() {
+                for (j, (sender, recver)) in [(wallet_1, wallet_2), (wallet_2, wallet_1)]
+                    .into_iter()
+                    .enumerate()
+                {
+                    if concurrency > 1 {
+                        rgb::progress(&format!("[worker {worker}, loop {}]", budget.label(i)));
+                    } else if j == 0 {
+                        println!("loop {}", budget.label(i));
+                    }
+                    let result = rgb::send_assets(
+                        sender,
+                        recver,
+                        assets,
+                        send_amount,
+                        test_mode,
+                        witness,
+                        transfer_opts,
+                    );
+                    write(result);
+                }
+            }
+        },
+    );
+}
+
+/// Run a job on a worker thread each, writing the transfer results to the report as they complete
+///
+/// A single job is run on the current thread.
+fn run_workers<J: Sync>(
+    report: &mut Report,
+    jobs: &[J],
+    work: impl Fn(usize, &J, &mut dyn FnMut(TransferResult)) + Sync,
+) {
+    if let [job] = jobs {
+        work(0, job, &mut |result| report.write(result));
+        return;
     }
+    let report = Mutex::new(report);
+    std::thread::scope(|scope| {
+        for (worker, job) in jobs.iter().enumerate() {
+            let (report, work) = (&report, &work);
+            scope.spawn(move || {
+                rgb::buffer_progress();
+                work(worker, job, &mut |result| {
+                    report.lock().unwrap().write(result)
+                });
+            });
+        }
+    });
 }
 
-pub(crate) fn merge_histories(opts: Opts, loops: u16) {
+pub(crate) fn merge_histories(opts: Opts, report: &mut Report, loops: u16) {
     let ScenarioOpts {
         data_dir,
-        output,
         send_amount,
         utxo_num: utxos,
         utxo_size,
         verbose,
         witness,
+        transfer_opts,
+        schema,
+        media_size,
+        concurrency: _,
+        seed: _,
     } = get_scenario_opts(opts);
-    let mut report_file = fs::File::create(output).expect("file should have been created");
-    write_report_header(&mut report_file);
 
     println!("\nsetup wallets");
     let num_wallets = 6u8;
@@ -152,9 +293,14 @@ pub(crate) fn merge_histories(opts: Opts, loops: u16) {
 
     // issue asset and split between initial pair of wallets
     println!("\nissue asset (2 allocations)");
-    let asset = wallets[0].issue_nia(vec![send_amount, send_amount], &TestMode::NoErrorHandling);
+    let asset = wallets[0].issue(
+        schema.for_issuance(0, 2),
+        vec![send_amount, send_amount],
+        media_size,
+        &TestMode::NoErrorHandling,
+    );
     println!("asset ID: {}", &asset.asset_id);
-    let assets = vec![(asset.asset_id, asset.ticker)];
+    let assets = vec![asset];
 
     println!("\nsend issued assets to 2 empty wallets");
     let result = rgb::send_assets(
@@ -164,8 +310,9 @@ pub(crate) fn merge_histories(opts: Opts, loops: u16) {
         send_amount,
         &TestMode::NoErrorHandling,
         witness,
+        transfer_opts,
     );
-    write_report_line(&mut report_file, &result);
+    report.write(result);
     let result = rgb::send_assets(
         &wallets[0],
         &wallets[2],
@@ -173,8 +320,9 @@ pub(crate) fn merge_histories(opts: Opts, loops: u16) {
         send_amount,
         &TestMode::NoErrorHandling,
         witness,
+        transfer_opts,
     );
-    write_report_line(&mut report_file, &result);
+    report.write(result);
 
     // RGB asset send loop to create asset transition histories
     println!("\nsend loops to extend the transition history");
@@ -188,8 +336,9 @@ pub(crate) fn merge_histories(opts: Opts, loops: u16) {
                 send_amount,
                 &TestMode::NoErrorHandling,
                 witness,
+                transfer_opts,
             );
-            write_report_line(&mut report_file, &result);
+            report.write(result);
             let result = rgb::send_assets(
                 wallet_pair.1,
                 wallet_pair.0,
@@ -197,8 +346,9 @@ pub(crate) fn merge_histories(opts: Opts, loops: u16) {
                 send_amount,
                 &TestMode::NoErrorHandling,
                 witness,
+                transfer_opts,
             );
-            write_report_line(&mut report_file, &result);
+            report.write(result);
         }
     }
 
@@ -213,8 +363,9 @@ pub(crate) fn merge_histories(opts: Opts, loops: u16) {
         send_amount,
         &TestMode::NoErrorHandling,
         witness,
+        transfer_opts,
     );
-    write_report_line(&mut report_file, &result);
+    report.write(result);
     let result = rgb::send_assets(
         wallet_last_2,
         &wallets[0],
@@ -222,8 +373,9 @@ pub(crate) fn merge_histories(opts: Opts, loops: u16) {
         send_amount,
         &TestMode::NoErrorHandling,
         witness,
+        transfer_opts,
     );
-    write_report_line(&mut report_file, &result);
+    report.write(result);
 
     let merge_amount = send_amount * 2;
 
@@ -236,8 +388,9 @@ pub(crate) fn merge_histories(opts: Opts, loops: u16) {
         merge_amount,
         &TestMode::NoErrorHandling,
         witness,
+        transfer_opts,
     );
-    write_report_line(&mut report_file, &result);
+    report.write(result);
 
     // send back to issuer wallet (spend merged histories)
     println!("\nspend merged histories");
@@ -248,8 +401,9 @@ pub(crate) fn merge_histories(opts: Opts, loops: u16) {
         merge_amount,
         &TestMode::NoErrorHandling,
         witness,
+        transfer_opts,
     );
-    write_report_line(&mut report_file, &result);
+    report.write(result);
 
     if verbose {
         println!("\nfinal wallet unspents and related RGB allocations:");
@@ -257,28 +411,35 @@ pub(crate) fn merge_histories(opts: Opts, loops: u16) {
     };
 }
 
-pub(crate) fn merge_utxos(opts: Opts, num_assets: u8, loops: u16) {
+pub(crate) fn merge_utxos(opts: Opts, report: &mut Report, num_assets: u8, loops: u16) {
     let ScenarioOpts {
         data_dir,
-        output,
         send_amount,
         utxo_num: utxos,
         utxo_size,
         verbose,
         witness,
+        transfer_opts,
+        schema,
+        media_size,
+        concurrency: _,
+        seed: _,
     } = get_scenario_opts(opts);
-    let mut report_file = fs::File::create(output).expect("file should have been created");
-    write_report_header(&mut report_file);
 
     println!("\nsetup wallets and issue assets");
     let mut issue_wallets = Vec::with_capacity(num_assets as usize);
-    let mut assets: Vec<(String, String)> = Vec::with_capacity(num_assets as usize);
+    let mut assets: Vec<RgbAsset> = Vec::with_capacity(num_assets as usize);
     for i in 0..num_assets {
         let mut wallet = get_wallet(&data_dir, i, utxos, utxo_size * loops as u32, None);
-        let asset = wallet.issue_nia(vec![send_amount], &TestMode::NoErrorHandling);
+        let asset = wallet.issue(
+            schema.for_issuance(i as usize, 1),
+            vec![send_amount],
+            media_size,
+            &TestMode::NoErrorHandling,
+        );
 
         issue_wallets.push(wallet);
-        assets.push((asset.asset_id.clone(), asset.ticker.clone()));
+        assets.push(asset);
     }
 
     // create state transition history for wallets
@@ -302,8 +463,9 @@ pub(crate) fn merge_utxos(opts: Opts, num_assets: u8, loops: u16) {
                 send_amount,
                 &TestMode::NoErrorHandling,
                 witness,
+                transfer_opts,
             );
-            write_report_line(&mut report_file, &result);
+            report.write(result);
             let result = rgb::send_assets(
                 &receiver,
                 sender,
@@ -311,8 +473,9 @@ pub(crate) fn merge_utxos(opts: Opts, num_assets: u8, loops: u16) {
                 send_amount,
                 &TestMode::NoErrorHandling,
                 witness,
+                transfer_opts,
             );
-            write_report_line(&mut report_file, &result);
+            report.write(result);
         }
     }
 
@@ -332,8 +495,9 @@ pub(crate) fn merge_utxos(opts: Opts, num_assets: u8, loops: u16) {
             send_amount,
             &TestMode::NoErrorHandling,
             witness,
+            transfer_opts,
         );
-        write_report_line(&mut report_file, &result);
+        report.write(result);
     }
 
     println!("\nspend all assets (single UTXO)");
@@ -348,8 +512,9 @@ pub(crate) fn merge_utxos(opts: Opts, num_assets: u8, loops: u16) {
         send_amount,
         &TestMode::NoErrorHandling,
         witness,
+        transfer_opts,
     );
-    write_report_line(&mut report_file, &result);
+    report.write(result);
 
     if verbose {
         println!("\nfinal wallet unspents and related RGB allocations:");
@@ -357,83 +522,112 @@ pub(crate) fn merge_utxos(opts: Opts, num_assets: u8, loops: u16) {
     };
 }
 
-pub(crate) fn random_wallets(opts: Opts, loops: u16, num_wallets: u8) {
+pub(crate) fn random_wallets(opts: Opts, report: &mut Report, loops: u16, num_wallets: u8) {
+    let budget = Budget::new(loops, opts.duration);
     let ScenarioOpts {
         data_dir,
-        output,
         send_amount,
         utxo_num: utxos,
         utxo_size,
         verbose: _,
         witness,
+        transfer_opts,
+        schema,
+        media_size,
+        concurrency,
+        seed,
     } = get_scenario_opts(opts);
-    let mut report_file = fs::File::create(output).expect("file should have been created");
-    write_report_header(&mut report_file);
-
-    println!("\nsetup wallets");
-    let mut wallets = Vec::with_capacity(num_wallets as usize);
-    for i in 0..num_wallets {
-        let walletinfo = get_wallet(&data_dir, i, utxos, utxo_size * loops as u32, None);
-        wallets.push(walletinfo);
-    }
-
-    println!("\nissue asset");
-    let asset = wallets[0].issue_nia(vec![send_amount], &TestMode::NoErrorHandling);
-    let asset = vec![(asset.asset_id, asset.ticker)];
-
-    println!("\nsend assets to randomly-selected wallets");
-    let mut last_index = 0;
-    let len = loops.to_string().len();
-    let mut rngthrd = if witness {
-        Some(rand::thread_rng())
+    // a timed run can outlast the funds and UTXOs set up for `loops` loops
+    let test_mode = &if budget.is_timed() {
+        TestMode::HandleUtxoErrors { utxos, utxo_size }
     } else {
-        None
+        TestMode::NoErrorHandling
     };
-    for i in 1..=loops {
-        let mut index = rand::thread_rng().gen_range(0..num_wallets as usize);
-        while index == last_index {
-            index = rand::thread_rng().gen_range(0..num_wallets as usize);
+
+    // one group of wallets, with its own asset, per worker
+    let mut groups = Vec::with_capacity(concurrency as usize);
+    for worker in 0..concurrency {
+        println!("\nsetup wallets");
+        let mut wallets = Vec::with_capacity(num_wallets as usize);
+        for i in 0..num_wallets {
+            let walletinfo = get_wallet(
+                &data_dir,
+                worker * num_wallets + i,
+                utxos,
+                utxo_size * loops as u32,
+                None,
+            );
+            wallets.push(walletinfo);
         }
-        print!("[{i:len$}/{loops}] ");
-        let result = rgb::send_assets(
-            &wallets[last_index],
-            &wallets[index],
-            &asset,
-            send_amount,
+
+        println!("\nissue asset");
+        let asset = wallets[0].issue(
+            schema.for_issuance(worker as usize, 1),
+            vec![send_amount],
+            media_size,
             &TestMode::NoErrorHandling,
-            if let Some(rng) = rngthrd.as_mut() {
-                rng.gen_bool(0.5)
-            } else {
-                false
-            },
         );
-        last_index = index;
-        write_report_line(&mut report_file, &result);
+        groups.push((wallets, vec![asset]));
     }
+
+    println!("\nsend assets to randomly-selected wallets");
+    run_workers(report, &groups, |worker, (wallets, asset), write| {
+        let mut last_index = 0;
+        let mut rng = StdRng::seed_from_u64(seed.wrapping_add(worker as u64));
+        for i in budget.iter() {
+            let mut index = rng.gen_range(0..num_wallets as usize);
+            while index == last_index {
+                index = rng.gen_range(0..num_wallets as usize);
+            }
+            if concurrency > 1 {
+                rgb::progress(&format!("[worker {worker}, {}] ", budget.label(i)));
+            } else {
+                rgb::progress(&format!("[{}] ", budget.label(i)));
+            }
+            let result = rgb::send_assets(
+                &wallets[last_index],
+                &wallets[index],
+                asset,
+                send_amount,
+                test_mode,
+                if witness { rng.gen_bool(0.5) } else { false },
+                transfer_opts,
+            );
+            // on failure the next transfer is sent again from the same wallet
+            if result.succeeded() {
+                last_index = index;
+            }
+            write(result);
+        }
+    });
 }
 
 pub(crate) fn random_transfers(
     opts: Opts,
+    report: &mut Report,
     num_wallets: u8,
     num_assets: u8,
     max_allocations_per_utxo: u32,
     loops: u16,
 ) {
+    let budget = Budget::new(loops, opts.duration);
     let ScenarioOpts {
         data_dir,
-        output,
         send_amount,
         utxo_num: utxos,
         utxo_size,
         verbose: _,
         witness,
+        transfer_opts,
+        schema,
+        media_size,
+        concurrency: _,
+        seed,
     } = get_scenario_opts(opts);
     let do_handle_errors = &TestMode::HandleUtxoErrors { utxos, utxo_size };
-    let mut report_file = fs::File::create(output).expect("file should have been created");
-    write_report_header(&mut report_file);
 
     println!("\nsetup {num_wallets} wallets");
-    let mut rng = rand::thread_rng();
+    let mut rng = StdRng::seed_from_u64(seed);
     let mut wallets: Vec<WalletWrapper> = Vec::with_capacity(num_wallets as usize);
     for i in 0..num_wallets {
         let wallet = get_wallet(
@@ -448,28 +642,26 @@ pub(crate) fn random_transfers(
 
     print!("\nissue {num_assets} asset(s)");
     std::io::stdout().flush().unwrap();
-    let mut asset_ids: Vec<AssetNIA> = Vec::new();
-    for _i in 0..num_assets {
+    let mut asset_ids: Vec<RgbAsset> = Vec::new();
+    for i in 0..num_assets {
         let wallet_index = rng.gen_range(0..wallets.len());
-        let new_asset = wallets[wallet_index].issue_nia(vec![send_amount], do_handle_errors);
+        let new_asset = wallets[wallet_index].issue(
+            schema.for_issuance(i as usize, 1),
+            vec![send_amount],
+            media_size,
+            do_handle_errors,
+        );
         print!(" {},", new_asset.ticker);
         std::io::stdout().flush().unwrap();
         asset_ids.push(new_asset);
     }
 
     println!("\ntransfers");
-    let len = loops.to_string().len();
-    for i in 1..=loops {
+    for i in budget.iter() {
+        let mut rng = transfer_rng(seed, i);
         let mut wallet_indexes: Vec<usize> = (0..wallets.len()).collect();
         wallet_indexes.shuffle(&mut rng);
-        let has_spendable = |i: &usize| {
-            wallets[*i]
-                .list_assets()
-                .nia
-                .unwrap()
-                .iter()
-                .any(|a| a.balance.spendable > 0)
-        };
+        let has_spendable = |i: &usize| !wallets[*i].spendable_assets().is_empty();
         let sender_index_pos = wallet_indexes
             .iter()
             .position(has_spendable)
@@ -479,34 +671,317 @@ pub(crate) fn random_transfers(
         let receiver_index = wallet_indexes.pop().expect("wallet should be available");
         let receiver = &wallets[receiver_index];
 
-        let sender_nia_assets = sender.list_assets().nia.unwrap();
-        let mut spendable_assets: Vec<&AssetNIA> = sender_nia_assets
-            .iter()
-            .filter(|asset| asset.balance.spendable > 0)
-            .collect();
+        let mut spendable_assets = sender.spendable_assets();
 
         spendable_assets.shuffle(&mut rng);
-        let asset = spendable_assets
+        let (asset, asset_balance) = spendable_assets
             .pop()
             .expect("spendable asset should be available");
-        let asset_balance = asset.balance.spendable;
 
-        print!("[{i:len$}/{loops}] ");
+        print!("[{}] ", budget.label(i));
         std::io::stdout().flush().unwrap();
 
-        let p = rand::thread_rng().gen_range(1..=10);
+        let p = rng.gen_range(1..=10);
         let balance_frac = asset_balance / p;
         let tx_amount = cmp::max(1, balance_frac);
 
         let result = rgb::send_assets(
             sender,
             receiver,
-            &[(asset.asset_id.clone(), asset.ticker.clone())],
+            &[asset],
             tx_amount,
             do_handle_errors,
             if witness { rng.gen_bool(0.5) } else { false },
+            transfer_opts,
+        );
+
+        report.write(result);
+    }
+}
+
+pub(crate) fn batch_block(opts: Opts, report: &mut Report, num_senders: u8, loops: u16) {
+    let ScenarioOpts {
+        data_dir,
+        send_amount,
+        utxo_num: utxos,
+        utxo_size,
+        verbose: _,
+        witness,
+        transfer_opts,
+        schema,
+        media_size,
+        concurrency: _,
+        seed: _,
+    } = get_scenario_opts(opts);
+    let do_handle_errors = &TestMode::HandleUtxoErrors { utxos, utxo_size };
+
+    println!("\nsetup {num_senders} sender wallets and a receiver wallet");
+    let mut senders = Vec::with_capacity(num_senders as usize);
+    for i in 0..num_senders {
+        let wallet = get_wallet(&data_dir, i, utxos, utxo_size * loops as u32, None);
+        senders.push(wallet);
+    }
+    let receiver = get_wallet(&data_dir, num_senders, utxos, utxo_size, None);
+
+    print!("\nissue {num_senders} asset(s) ({loops} allocations each)");
+    std::io::stdout().flush().unwrap();
+    let mut assets = Vec::with_capacity(num_senders as usize);
+    for (i, sender) in senders.iter_mut().enumerate() {
+        let asset = sender.issue(
+            schema.for_issuance(i, loops as usize),
+            vec![send_amount; loops as usize],
+            media_size,
+            do_handle_errors,
+        );
+        print!(" {},", asset.ticker);
+        std::io::stdout().flush().unwrap();
+        assets.push(asset);
+    }
+
+    println!("\nbatches of {num_senders} transfers confirmed by a single block");
+    for i in 1..=loops {
+        println!("batch {i}/{loops}");
+        let transfers: Vec<BatchTransfer> = senders
+            .iter()
+            .zip(&assets)
+            .map(|(sender, asset)| BatchTransfer {
+                sender,
+                assets: vec![asset.clone()],
+                witness,
+            })
+            .collect();
+        let results = rgb::send_assets_batch(
+            &transfers,
+            &receiver,
+            send_amount,
+            do_handle_errors,
+            transfer_opts,
+        );
+        report.write_group(results);
+    }
+}
+
+pub(crate) fn mempool_chain(opts: Opts, report: &mut Report, num_wallets: u8, loops: u16) {
+    let ScenarioOpts {
+        data_dir,
+        send_amount,
+        utxo_num: utxos,
+        utxo_size,
+        verbose: _,
+        witness,
+        transfer_opts,
+        schema,
+        media_size,
+        concurrency: _,
+        seed: _,
+    } = get_scenario_opts(opts);
+    let do_handle_errors = &TestMode::HandleUtxoErrors { utxos, utxo_size };
+
+    println!("\nsetup {num_wallets} wallets");
+    let mut wallets = Vec::with_capacity(num_wallets as usize);
+    for i in 0..num_wallets {
+        wallets.push(get_wallet(&data_dir, i, utxos, utxo_size, None));
+    }
+
+    let assets = vec![wallets[0].issue(
+        schema.for_issuance(0, 1),
+        vec![send_amount],
+        media_size,
+        do_handle_errors,
+    )];
+    println!("\nissued asset {}", assets[0].ticker);
+
+    println!("\nchains of {num_wallets} unconfirmed transfers confirmed by a single block");
+    let hops: Vec<ChainHop> = wallets
+        .iter()
+        .enumerate()
+        .map(|(i, sender)| ChainHop {
+            sender,
+            recver: &wallets[(i + 1) % wallets.len()],
+            witness,
+        })
+        .collect();
+    // refresh times of succeeded transfers, by unconfirmed depth
+    let mut depth_times: Vec<Vec<(u64, u64)>> = vec![vec![]; hops.len()];
+    for i in 1..=loops {
+        println!("chain {i}/{loops}");
+        let results =
+            rgb::send_assets_chain(&hops, &assets, send_amount, do_handle_errors, transfer_opts);
+        let failed = results.iter().any(|r| !r.succeeded());
+        for (depth, result) in results.iter().enumerate() {
+            // replayed transfers have no times
+            if result.succeeded() && !result.replayed {
+                let t = &result.times;
+                depth_times[depth].push((
+                    t.recv_refresh_1 + t.recv_refresh_2,
+                    t.send_refresh_1 + t.send_refresh_2,
+                ));
+            }
+        }
+        report.write_group(results);
+        if failed {
+            // the asset is not where the next chain expects it
+            println!("chain {i} failed, stopping");
+            break;
+        }
+    }
+
+    println!(
+        "\nmean refresh times (ms) by unconfirmed depth\n{:>5} {:>10} {:>10} {:>10}",
+        "depth", "receiver", "sender", "transfers"
+    );
+    let mut points = vec![];
+    for (depth, times) in depth_times.iter().enumerate() {
+        if times.is_empty() {
+            continue;
+        }
+        let recv: Vec<f64> = times.iter().map(|(r, _)| *r as f64).collect();
+        let send: Vec<f64> = times.iter().map(|(_, s)| *s as f64).collect();
+        println!(
+            "{:>5} {:>10.1} {:>10.1} {:>10}",
+            depth + 1,
+            summary::mean(&recv),
+            summary::mean(&send),
+            times.len()
+        );
+        points.extend(
+            times
+                .iter()
+                .map(|(r, s)| ((depth + 1) as f64, (r + s) as f64)),
+        );
+    }
+    match summary::LinearFit::new(&points) {
+        Some(f) => println!(
+            "refresh time growth: {:.1} ms per hop (intercept {:.1}, r2 {:.3})",
+            f.slope, f.intercept, f.r2
+        ),
+        None => println!("refresh time growth: n/a"),
+    }
+}
+
+pub(crate) fn reorg(opts: Opts, report: &mut Report, conflict: bool, blocks: u32, loops: u8) {
+    let ScenarioOpts {
+        data_dir,
+        send_amount,
+        utxo_num: utxos,
+        utxo_size,
+        verbose: _,
+        witness,
+        transfer_opts,
+        schema,
+        media_size,
+        concurrency: _,
+        seed: _,
+    } = get_scenario_opts(opts);
+    let do_handle_errors = &TestMode::HandleUtxoErrors { utxos, utxo_size };
+
+    println!("\nsetup receiver wallet");
+    let receiver = get_wallet(&data_dir, 0, utxos, utxo_size, None);
+
+    let kind = if conflict {
+        "with a conflicting spend"
+    } else {
+        "re-confirming the transfer"
+    };
+    println!("\ntransfers reorged out by a competing chain of {blocks} block(s) {kind}");
+    for i in 1..=loops {
+        println!("reorg {i}/{loops}");
+        // each transfer gets a new sender, as the conflicting spend drains all its UTXOs
+        let mut sender = get_wallet(&data_dir, i, utxos, utxo_size, None);
+        let asset = sender.issue(
+            schema.for_issuance(i as usize - 1, 1),
+            vec![send_amount],
+            media_size,
+            do_handle_errors,
+        );
+        let conflicting_tx = conflict.then(|| sender.conflicting_tx());
+        let mut result = rgb::send_assets(
+            &sender,
+            &receiver,
+            std::slice::from_ref(&asset),
+            send_amount,
+            do_handle_errors,
+            witness,
+            transfer_opts,
         );
+        if result.succeeded() {
+            rgb::reorg_transfer(
+                &mut result,
+                &sender,
+                &receiver,
+                &asset,
+                blocks,
+                conflicting_tx.as_deref(),
+                transfer_opts,
+            );
+        }
+        report.write(result);
+    }
+}
 
-        write_report_line(&mut report_file, result.as_str());
+pub(crate) fn batch_send(
+    opts: Opts,
+    report: &mut Report,
+    num_assets: u8,
+    num_recipients: u8,
+    loops: u16,
+) {
+    let ScenarioOpts {
+        data_dir,
+        send_amount,
+        utxo_num: utxos,
+        utxo_size,
+        verbose: _,
+        witness,
+        transfer_opts,
+        schema,
+        media_size,
+        concurrency: _,
+        seed: _,
+    } = get_scenario_opts(opts);
+    let do_handle_errors = &TestMode::HandleUtxoErrors { utxos, utxo_size };
+
+    println!("\nsetup sender wallet and {num_recipients} receiver wallets");
+    let mut sender = get_wallet(&data_dir, 0, utxos, utxo_size * loops as u32, None);
+    let mut receivers = Vec::with_capacity(num_recipients as usize);
+    for i in 1..=num_recipients {
+        receivers.push(get_wallet(&data_dir, i, utxos, utxo_size, None));
+    }
+
+    print!("\nissue {num_assets} asset(s)");
+    std::io::stdout().flush().unwrap();
+    let issue_amount = send_amount * num_recipients as u64 * loops as u64;
+    let mut assets = Vec::with_capacity(num_assets as usize);
+    for i in 0..num_assets {
+        // UDA assets cannot be split between recipients
+        let asset = sender.issue(
+            schema.for_issuance(i as usize, num_recipients as usize),
+            vec![issue_amount],
+            media_size,
+            do_handle_errors,
+        );
+        print!(" {},", asset.ticker);
+        std::io::stdout().flush().unwrap();
+        assets.push(asset);
+    }
+
+    // alternate blinded and witness recipients if witness sends are enabled
+    let recvers: Vec<(&WalletWrapper, bool)> = receivers
+        .iter()
+        .enumerate()
+        .map(|(i, recver)| (recver, witness && i % 2 == 1))
+        .collect();
+    println!("\nsend to {num_recipients} recipients per asset in a single transaction");
+    for i in 1..=loops {
+        println!("loop {i}/{loops}");
+        let results = rgb::send_assets_multi(
+            &sender,
+            &recvers,
+            &assets,
+            send_amount,
+            do_handle_errors,
+            transfer_opts,
+        );
+        report.write_group(results);
     }
 }
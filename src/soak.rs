@@ -0,0 +1,157 @@
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+use crate::manifest;
+use crate::report::TransferResult;
+use crate::summary::{self, STEPS};
+
+/// Number of loops a scenario runs, or the time it keeps running for
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Budget {
+    Loops(u16),
+    Until(Instant),
+}
+
+impl Budget {
+    /// Budget of `loops` loops, or of the given duration from now if set
+    ///
+    /// A resumed run only gets the part of the duration not spent before it was interrupted.
+    pub(crate) fn new(loops: u16, duration: Option<Duration>) -> Self {
+        match duration {
+            Some(duration) => {
+                Budget::Until(Instant::now() + duration.saturating_sub(manifest::start_soak()))
+            }
+            None => Budget::Loops(loops),
+        }
+    }
+
+    /// Loop numbers, starting from 1, until the budget is spent
+    ///
+    /// With a duration, a loop is started as long as the deadline has not been reached.
+    pub(crate) fn iter(self) -> impl Iterator<Item = u64> {
+        (1..).take_while(move |i| match self {
+            Budget::Loops(loops) => *i <= loops as u64,
+            Budget::Until(deadline) => Instant::now() < deadline,
+        })
+    }
+
+    /// Progress label of the given loop, e.g. `3/16` or `3, 7h59m left`
+    pub(crate) fn label(&self, i: u64) -> String {
+        match self {
+            Budget::Loops(loops) => {
+                let len = loops.to_string().len();
+                format!("{i:len$}/{loops}")
+            }
+            Budget::Until(deadline) => format!(
+                "{i}, {} left",
+                format_duration(deadline.saturating_duration_since(Instant::now()))
+            ),
+        }
+    }
+
+    pub(crate) fn is_timed(&self) -> bool {
+        matches!(self, Budget::Until(_))
+    }
+}
+
+/// Parse a duration made of a number and a unit (s, m, h or d), e.g. `90s`, `30m` or `8h`
+pub(crate) fn parse_duration(s: &str) -> Result<Duration, String> {
+    let err = || format!("invalid duration '{s}', expected a number followed by s, m, h or d");
+    let unit_pos = s.find(|c: char| !c.is_ascii_digit()).ok_or_else(err)?;
+    let (value, unit) = s.split_at(unit_pos);
+    let value: u64 = value.parse().map_err(|_| err())?;
+    let secs = match unit {
+        "s" => value,
+        "m" => value * 60,
+        "h" => value * 3600,
+        "d" => value * 86400,
+        _ => return Err(err()),
+    };
+    if secs == 0 {
+        return Err(format!("invalid duration '{s}', must be greater than 0"));
+    }
+    Ok(Duration::from_secs(secs))
+}
+
+/// Format a duration as hours and minutes, or seconds if shorter than a minute
+pub(crate) fn format_duration(duration: Duration) -> String {
+    let secs = duration.as_secs();
+    match (secs / 3600, secs % 3600 / 60) {
+        (0, 0) => format!("{secs}s"),
+        (0, m) => format!("{m}m"),
+        (h, m) => format!("{h}h{m:02}m"),
+    }
+}
+
+/// Rolling statistics of a soak run, printed every interval
+///
+/// Each printout covers the transfers completed since the previous one, so a gradual degradation
+/// (e.g. due to database growth) shows up as increasing step times and decreasing throughput.
+pub(crate) struct Monitor {
+    interval: Duration,
+    start: Instant,
+    window_start: Instant,
+    window: Vec<TransferResult>,
+    total: usize,
+}
+
+impl Monitor {
+    pub(crate) fn new(interval: Duration) -> Self {
+        let now = Instant::now();
+        Monitor {
+            interval,
+            start: now,
+            window_start: now,
+            window: vec![],
+            total: 0,
+        }
+    }
+
+    /// Add a completed transfer, returning the rolling statistics if the interval has elapsed
+    pub(crate) fn record(&mut self, result: &TransferResult) -> Option<String> {
+        self.window.push(result.clone());
+        self.total += 1;
+        if self.window_start.elapsed() < self.interval {
+            return None;
+        }
+        let stats = self.stats();
+        self.window.clear();
+        self.window_start = Instant::now();
+        Some(stats)
+    }
+
+    fn stats(&self) -> String {
+        let mut out = String::new();
+        let minutes = self.window_start.elapsed().as_secs_f64() / 60.0;
+        let failed = self.window.iter().filter(|t| !t.succeeded()).count();
+        writeln!(
+            out,
+            "[{} elapsed] {} transfer(s) in total, last {}: {:.1} transfers/min, {failed} failed",
+            format_duration(self.start.elapsed()),
+            self.total,
+            format_duration(self.window_start.elapsed()),
+            self.window.len() as f64 / minutes,
+        )
+        .unwrap();
+        let medians: Vec<String> = STEPS
+            .iter()
+            .filter_map(|(label, time)| {
+                let mut values: Vec<f64> = self
+                    .window
+                    .iter()
+                    .filter(|t| t.succeeded() && summary::step_applies(label, t))
+                    .map(|t| time(&t.times) as f64)
+                    .collect();
+                if values.is_empty() {
+                    return None;
+                }
+                values.sort_by(|a, b| a.total_cmp(b));
+                Some(format!("{label} {:.0}", summary::percentile(&values, 50.0)))
+            })
+            .collect();
+        if !medians.is_empty() {
+            write!(out, "  median step times (ms): {}", medians.join(", ")).unwrap();
+        }
+        out
+    }
+}
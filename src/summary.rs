@@ -0,0 +1,447 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use crate::report::{BackupResult, StepTimes, TransferResult};
+use crate::sweep;
+
+/// Function extracting the time of a step
+pub(crate) type StepTime = fn(&StepTimes) -> u64;
+
+/// Transfer steps included in the summary, with the function extracting their time
+pub(crate) const STEPS: [(&str, StepTime); 6] = [
+    ("send", |t| t.send),
+    ("recv refresh 1", |t| t.recv_refresh_1),
+    ("send refresh 1", |t| t.send_refresh_1),
+    ("recv refresh 2", |t| t.recv_refresh_2),
+    ("send refresh 2", |t| t.send_refresh_2),
+    ("total", |t| t.total),
+];
+
+/// Waits for the regtest services, included in the total time but not carried out by rgb-lib
+pub(crate) const WAITS: [(&str, StepTime); 2] = [
+    ("mining", |t| t.mining),
+    ("electrum wait", |t| t.electrum_wait),
+];
+
+/// Whether the given step is carried out by a transfer
+///
+/// Donations are broadcast on send, so they skip the refreshes processing the ACK.
+pub(crate) fn step_applies(label: &str, transfer: &TransferResult) -> bool {
+    !(transfer.donation && matches!(label, "recv refresh 1" | "send refresh 1"))
+}
+
+/// Descriptive statistics of a set of values
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Stats {
+    pub(crate) min: f64,
+    pub(crate) max: f64,
+    pub(crate) mean: f64,
+    pub(crate) median: f64,
+    pub(crate) p90: f64,
+    pub(crate) p99: f64,
+}
+
+impl Stats {
+    /// Compute statistics, returning `None` if there are no values
+    pub(crate) fn new(values: &[f64]) -> Option<Self> {
+        if values.is_empty() {
+            return None;
+        }
+        let mut sorted = values.to_vec();
+        sorted.sort_by(|a, b| a.total_cmp(b));
+        Some(Stats {
+            min: sorted[0],
+            max: sorted[sorted.len() - 1],
+            mean: mean(&sorted),
+            median: percentile(&sorted, 50.0),
+            p90: percentile(&sorted, 90.0),
+            p99: percentile(&sorted, 99.0),
+        })
+    }
+}
+
+pub(crate) fn mean(values: &[f64]) -> f64 {
+    values.iter().sum::<f64>() / values.len() as f64
+}
+
+/// Percentile of sorted values, with linear interpolation between closest ranks
+pub(crate) fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = p / 100.0 * (sorted.len() - 1) as f64;
+    let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+}
+
+/// Least-squares fit of `y = slope * x + intercept`
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct LinearFit {
+    pub(crate) slope: f64,
+    pub(crate) intercept: f64,
+    pub(crate) r2: f64,
+}
+
+impl LinearFit {
+    /// Fit the given points, returning `None` if there are less than 2 distinct x values
+    pub(crate) fn new(points: &[(f64, f64)]) -> Option<Self> {
+        if points.len() < 2 {
+            return None;
+        }
+        let n = points.len() as f64;
+        let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+        let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+        let ss_xx: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+        let ss_yy: f64 = points.iter().map(|(_, y)| (y - mean_y).powi(2)).sum();
+        let ss_xy: f64 = points
+            .iter()
+            .map(|(x, y)| (x - mean_x) * (y - mean_y))
+            .sum();
+        if ss_xx == 0.0 {
+            return None;
+        }
+        let slope = ss_xy / ss_xx;
+        let r2 = if ss_yy == 0.0 {
+            1.0
+        } else {
+            ss_xy.powi(2) / (ss_xx * ss_yy)
+        };
+        Some(LinearFit {
+            slope,
+            intercept: mean_y - slope * mean_x,
+            r2,
+        })
+    }
+}
+
+/// Consignment sizes of each asset of succeeded transfers, by ticker, with the position of the
+/// transfer they belong to
+pub(crate) fn consignment_sizes(
+    transfers: &[&TransferResult],
+) -> BTreeMap<String, Vec<(f64, f64)>> {
+    let mut sizes: BTreeMap<String, Vec<(f64, f64)>> = BTreeMap::new();
+    for (i, transfer) in transfers.iter().enumerate() {
+        if !transfer.succeeded() {
+            continue;
+        }
+        for asset in &transfer.assets {
+            sizes
+                .entry(asset.ticker.clone())
+                .or_default()
+                .push((i as f64, asset.consignment_size as f64));
+        }
+    }
+    sizes
+}
+
+fn stats_line(out: &mut String, label: &str, values: &[f64]) {
+    if let Some(s) = Stats::new(values) {
+        writeln!(
+            out,
+            "{label:16} {:>10.0} {:>10.0} {:>10.1} {:>10.1} {:>10.1} {:>10.1}",
+            s.min, s.max, s.mean, s.median, s.p90, s.p99
+        )
+        .unwrap();
+    }
+}
+
+fn fit_line(out: &mut String, label: &str, points: &[(f64, f64)]) {
+    match LinearFit::new(points) {
+        Some(f) => writeln!(
+            out,
+            "{label:24} {:>12.3} {:>12.1} {:>8.3}",
+            f.slope, f.intercept, f.r2
+        ),
+        None => writeln!(out, "{label:24} {:>12}", "n/a"),
+    }
+    .unwrap();
+}
+
+/// Linear fits of the costs of the succeeded transfers of a run against their position in the
+/// run, so that failed transfers don't shift the following ones
+fn fit_lines(out: &mut String, transfers: &[&TransferResult]) {
+    let send_times: Vec<(f64, f64)> = transfers
+        .iter()
+        .enumerate()
+        .filter(|(_, t)| t.succeeded())
+        .map(|(i, t)| (i as f64, t.times.send as f64))
+        .collect();
+    fit_line(out, "send time (ms)", &send_times);
+    for (ticker, points) in &consignment_sizes(transfers) {
+        fit_line(out, &format!("{ticker} size (bytes)"), points);
+    }
+    let backups: Vec<(f64, &BackupResult)> = transfers
+        .iter()
+        .enumerate()
+        .filter(|(_, t)| t.succeeded())
+        .flat_map(|(i, t)| t.backups.iter().map(move |b| (i as f64, b)))
+        .collect();
+    if !backups.is_empty() {
+        let points: Vec<(f64, f64)> = backups.iter().map(|(i, b)| (*i, b.size as f64)).collect();
+        fit_line(out, "backup size (bytes)", &points);
+        let points: Vec<(f64, f64)> = backups.iter().map(|(i, b)| (*i, b.time as f64)).collect();
+        fit_line(out, "backup time (ms)", &points);
+    }
+}
+
+fn failures(out: &mut String, transfers: &[&TransferResult]) {
+    let mut counts: BTreeMap<(&str, &str), usize> = BTreeMap::new();
+    for failure in transfers.iter().filter_map(|t| t.failure.as_ref()) {
+        *counts.entry((&failure.step, &failure.error)).or_default() += 1;
+    }
+    writeln!(
+        out,
+        "\nfailures\n{:16} {:32} {:>6}",
+        "step", "error", "count"
+    )
+    .unwrap();
+    for ((step, error), count) in counts {
+        writeln!(out, "{step:16} {error:32} {count:>6}").unwrap();
+    }
+}
+
+/// Two-sided 95% critical values of Student's t distribution, by degrees of freedom (1-30)
+const T_95: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+    2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+    2.052, 2.048, 2.045, 2.042,
+];
+
+/// Mean of the given values with the half-width of its 95% confidence interval, returning `None`
+/// if there are less than 2 values
+pub(crate) fn confidence_interval(values: &[f64]) -> Option<(f64, f64)> {
+    if values.len() < 2 {
+        return None;
+    }
+    let n = values.len() as f64;
+    let m = mean(values);
+    let variance = values.iter().map(|v| (v - m).powi(2)).sum::<f64>() / (n - 1.0);
+    // normal approximation for large samples
+    let t = T_95.get(values.len() - 2).copied().unwrap_or(1.960);
+    Some((m, t * (variance / n).sqrt()))
+}
+
+fn interval_cell(values: &[f64]) -> String {
+    match confidence_interval(values) {
+        Some((m, h)) => format!("{:>10.1} {:>9}", m, format!("±{h:.1}")),
+        None => format!("{:>10} {:>9}", "n/a", ""),
+    }
+}
+
+/// Transfers of each run (sweep run or repetition), each starting from fresh wallets, by
+/// parameter values in the order the runs were carried out
+fn runs<'a>(
+    transfers: impl IntoIterator<Item = &'a TransferResult>,
+) -> Vec<(String, Vec<&'a TransferResult>)> {
+    let mut runs: Vec<(String, Vec<&TransferResult>)> = vec![];
+    for transfer in transfers {
+        let params = sweep::format_params(&transfer.params);
+        match runs.iter_mut().find(|(p, _)| *p == params) {
+            Some((_, run)) => run.push(transfer),
+            None => runs.push((params, vec![transfer])),
+        }
+    }
+    runs
+}
+
+/// Build the summary of a repeated run, aggregating timings across repetitions
+///
+/// For each step and wait the median of each repetition is aggregated, then the time of the
+/// transfer at each position in the scenario. Failed transfers are excluded.
+pub(crate) fn summarize_repetitions(transfers: &[TransferResult]) -> String {
+    let repetitions = runs(transfers);
+    let mut out = String::new();
+    writeln!(
+        out,
+        "{} repetition(s), mean and 95% confidence interval (ms)\n\n\
+        median times\n{:16} {:>10} {:>9}",
+        repetitions.len(),
+        "",
+        "mean",
+        "ci"
+    )
+    .unwrap();
+    for (label, time) in STEPS.iter().chain(&WAITS) {
+        let medians: Vec<f64> = repetitions
+            .iter()
+            .filter_map(|(_, transfers)| {
+                let mut values: Vec<f64> = transfers
+                    .iter()
+                    .filter(|t| t.succeeded() && step_applies(label, t))
+                    .map(|t| time(&t.times) as f64)
+                    .collect();
+                values.sort_by(|a, b| a.total_cmp(b));
+                (!values.is_empty()).then(|| percentile(&values, 50.0))
+            })
+            .collect();
+        writeln!(out, "{label:16} {}", interval_cell(&medians)).unwrap();
+    }
+
+    writeln!(
+        out,
+        "\ntimes by transfer position\n{:16} {:>8} {:>5} {:>10} {:>9}",
+        "", "position", "n", "mean", "ci"
+    )
+    .unwrap();
+    let positions = repetitions.iter().map(|(_, t)| t.len()).max().unwrap_or(0);
+    for (label, time) in STEPS.iter().chain(&WAITS) {
+        for position in 0..positions {
+            let values: Vec<f64> = repetitions
+                .iter()
+                .filter_map(|(_, transfers)| transfers.get(position))
+                .filter(|t| t.succeeded() && step_applies(label, t))
+                .map(|t| time(&t.times) as f64)
+                .collect();
+            writeln!(
+                out,
+                "{label:16} {:>8} {:>5} {}",
+                position + 1,
+                values.len(),
+                interval_cell(&values)
+            )
+            .unwrap();
+        }
+    }
+    out
+}
+
+/// Build the summary of each combination of parameter values of a sweep, merging repeats
+pub(crate) fn summarize_sweep(transfers: &[TransferResult]) -> String {
+    let mut runs: BTreeMap<String, Vec<&TransferResult>> = BTreeMap::new();
+    for transfer in transfers {
+        let mut params = transfer.params.clone();
+        params.remove("repeat");
+        runs.entry(sweep::format_params(&params))
+            .or_default()
+            .push(transfer);
+    }
+    let width = runs.keys().map(|k| k.len()).max().unwrap_or(0).max(6);
+    let mut out = String::new();
+    writeln!(
+        out,
+        "sweep (median times in ms)\n{:width$} {:>10} {:>8} {:>10} {:>10} {:>10}",
+        "params", "transfers", "failed", "send", "total", "p90 total"
+    )
+    .unwrap();
+    for (params, transfers) in runs {
+        let succeeded: Vec<&TransferResult> = transfers
+            .iter()
+            .copied()
+            .filter(|t| t.succeeded())
+            .collect();
+        let sorted = |time: StepTime| {
+            let mut values: Vec<f64> = succeeded.iter().map(|t| time(&t.times) as f64).collect();
+            values.sort_by(|a, b| a.total_cmp(b));
+            values
+        };
+        let (send, total) = (sorted(|t| t.send), sorted(|t| t.total));
+        write!(
+            out,
+            "{params:width$} {:>10} {:>8}",
+            transfers.len(),
+            transfers.len() - succeeded.len()
+        )
+        .unwrap();
+        if succeeded.is_empty() {
+            writeln!(out).unwrap();
+            continue;
+        }
+        writeln!(
+            out,
+            " {:>10.1} {:>10.1} {:>10.1}",
+            percentile(&send, 50.0),
+            percentile(&total, 50.0),
+            percentile(&total, 90.0)
+        )
+        .unwrap();
+    }
+    out
+}
+
+/// Build the end-of-run summary of the given transfers
+///
+/// Failed transfers are counted separately and excluded from the statistics.
+pub(crate) fn summarize(transfers: &[TransferResult]) -> String {
+    let mut out = String::new();
+    let (succeeded, failed): (Vec<&TransferResult>, Vec<&TransferResult>) =
+        transfers.iter().partition(|t| t.succeeded());
+    writeln!(
+        out,
+        "summary of {} transfer(s), {} failed",
+        transfers.len(),
+        failed.len()
+    )
+    .unwrap();
+    if !failed.is_empty() {
+        failures(&mut out, &failed);
+    }
+    let runs = runs(transfers);
+    let transfers = &succeeded[..];
+    if transfers.is_empty() {
+        return out;
+    }
+    let header = format!(
+        "{:16} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
+        "", "min", "max", "mean", "median", "p90", "p99"
+    );
+
+    writeln!(out, "\nstep times (ms)\n{header}").unwrap();
+    for (label, time) in STEPS {
+        let values: Vec<f64> = transfers
+            .iter()
+            .filter(|t| step_applies(label, t))
+            .map(|t| time(&t.times) as f64)
+            .collect();
+        stats_line(&mut out, label, &values);
+    }
+    for (label, time) in WAITS {
+        let values: Vec<f64> = transfers.iter().map(|t| time(&t.times) as f64).collect();
+        stats_line(&mut out, label, &values);
+    }
+
+    let sizes = consignment_sizes(transfers);
+    writeln!(out, "\nconsignment sizes (bytes)\n{header}").unwrap();
+    for (ticker, points) in &sizes {
+        let values: Vec<f64> = points.iter().map(|(_, size)| *size).collect();
+        stats_line(&mut out, ticker, &values);
+    }
+
+    let mut schema_sizes: BTreeMap<&str, Vec<f64>> = BTreeMap::new();
+    for asset in transfers.iter().flat_map(|t| &t.assets) {
+        if !asset.schema.is_empty() {
+            schema_sizes
+                .entry(&asset.schema)
+                .or_default()
+                .push(asset.consignment_size as f64);
+        }
+    }
+    if schema_sizes.len() > 1 {
+        writeln!(out, "\nconsignment sizes by schema (bytes)\n{header}").unwrap();
+        for (schema, values) in &schema_sizes {
+            stats_line(&mut out, schema, values);
+        }
+    }
+
+    let backups: Vec<&BackupResult> = transfers.iter().flat_map(|t| &t.backups).collect();
+    if !backups.is_empty() {
+        writeln!(out, "\nwallet backups\n{header}").unwrap();
+        let sizes: Vec<f64> = backups.iter().map(|b| b.size as f64).collect();
+        stats_line(&mut out, "size (bytes)", &sizes);
+        let times: Vec<f64> = backups.iter().map(|b| b.time as f64).collect();
+        stats_line(&mut out, "time (ms)", &times);
+    }
+
+    // each run starts from fresh wallets, so fitting runs together would show their resets as drift
+    for (params, run) in &runs {
+        let title = if runs.len() > 1 {
+            format!(" ({params})")
+        } else {
+            String::new()
+        };
+        writeln!(
+            out,
+            "\nlinear fit against transfer position{title}\n{:24} {:>12} {:>12} {:>8}",
+            "", "slope", "intercept", "r2"
+        )
+        .unwrap();
+        fit_lines(&mut out, run);
+    }
+    out
+}
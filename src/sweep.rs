@@ -0,0 +1,219 @@
+use std::collections::BTreeMap;
+use std::str::FromStr;
+
+use clap::{CommandFactory, Parser};
+use serde::Serialize;
+
+use crate::opts::Opts;
+
+/// Parameter swept over a list of values, e.g. `loops=4,8,16` or `utxo-size=1000..=5000:1000`
+#[derive(Serialize, Clone, PartialEq, Eq, Debug)]
+pub struct SweepParam {
+    /// Long name of the option, without the leading dashes
+    pub name: String,
+    pub values: Vec<String>,
+}
+
+impl FromStr for SweepParam {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, values) = s
+            .split_once('=')
+            .ok_or_else(|| format!("invalid parameter '{s}', expected <NAME>=<VALUES>"))?;
+        let name = name.trim_start_matches("--").to_string();
+        let mut expanded = vec![];
+        for item in values.split(',') {
+            match item.split_once("..=") {
+                Some((start, end)) => expanded.extend(expand_range(start, end)?),
+                None if !item.is_empty() => expanded.push(item.to_string()),
+                None => return Err(format!("invalid parameter '{s}', empty value")),
+            }
+        }
+        Ok(SweepParam {
+            name,
+            values: expanded,
+        })
+    }
+}
+
+/// Expand an inclusive range of integers, with an optional step (e.g. `1000..=5000:1000`)
+fn expand_range(start: &str, end: &str) -> Result<Vec<String>, String> {
+    let err = || format!("invalid range '{start}..={end}', expected <START>..=<END>[:<STEP>]");
+    let (end, step) = end.split_once(':').unwrap_or((end, "1"));
+    let start: u64 = start.parse().map_err(|_| err())?;
+    let end: u64 = end.parse().map_err(|_| err())?;
+    let step: usize = step.parse().map_err(|_| err())?;
+    if step == 0 || start > end {
+        return Err(err());
+    }
+    Ok((start..=end).step_by(step).map(|v| v.to_string()).collect())
+}
+
+/// Global options applying to the whole sweep, which cannot be swept
+const RUN_OPTIONS: &[&str] = &[
+    "force",
+    "data-dir",
+    "output",
+    "format",
+    "summary",
+    "external-services",
+    "keep-services-on-failure",
+    "electrum-url",
+    "transport-endpoint",
+    "bitcoind-rpc-url",
+    "bitcoind-rpc-user",
+    "bitcoind-rpc-password",
+    "resume",
+    "duration",
+    "repeat",
+    "warmup",
+    "trace",
+    "seed",
+];
+
+/// Run of a sweep, with the parameter values identifying it
+pub(crate) struct SweepRun {
+    /// Parameter values, including the repeat number if the runs are repeated
+    pub(crate) params: BTreeMap<String, String>,
+    /// Repeat number, starting from 0
+    pub(crate) repeat: u8,
+    pub(crate) opts: Opts,
+}
+
+/// Options of each run of the cartesian product of the given parameter values, repeated
+/// `repeats` times
+///
+/// Each run is parsed from the global options the sweep was given, followed by the scenario
+/// command line, with the swept values added as global or scenario options according to the
+/// option they set.
+pub(crate) fn runs(
+    args: &[String],
+    params: &[SweepParam],
+    repeats: u8,
+    scenario: &[String],
+) -> Result<Vec<SweepRun>, String> {
+    let command = Opts::command();
+    let global_args = &args[..subcommand_position(&command, args)];
+    let subcommand = command
+        .find_subcommand(&scenario[0])
+        .filter(|c| !matches!(c.get_name(), "sweep" | "compare"))
+        .ok_or_else(|| format!("invalid sweep scenario '{}'", scenario[0]))?;
+    let has_option =
+        |c: &clap::Command, name: &str| c.get_arguments().any(|a| a.get_long() == Some(name));
+    let mut global = vec![];
+    for param in params {
+        if RUN_OPTIONS.contains(&param.name.as_str()) {
+            return Err(format!(
+                "invalid sweep parameter '{}': it applies to the whole sweep",
+                param.name
+            ));
+        }
+        if has_option(&command, &param.name) {
+            global.push(true);
+        } else if has_option(subcommand, &param.name) {
+            global.push(false);
+        } else {
+            return Err(format!(
+                "invalid sweep parameter '{}': not an option of {}",
+                param.name, scenario[0]
+            ));
+        }
+    }
+
+    // cartesian product of the parameter values, by index
+    let mut combinations: Vec<Vec<usize>> = vec![vec![]];
+    for param in params {
+        combinations = combinations
+            .into_iter()
+            .flat_map(|c| {
+                (0..param.values.len()).map(move |i| {
+                    let mut c = c.clone();
+                    c.push(i);
+                    c
+                })
+            })
+            .collect();
+    }
+
+    let mut runs = Vec::with_capacity(combinations.len() * repeats as usize);
+    for combination in combinations {
+        let mut run_args = vec!["rgb_lib_stress_test".to_string()];
+        run_args.extend_from_slice(global_args);
+        let mut scenario_args = scenario.to_vec();
+        let mut values = BTreeMap::new();
+        for ((param, i), global) in params.iter().zip(combination).zip(&global) {
+            let value = &param.values[i];
+            let args = if *global {
+                &mut run_args
+            } else {
+                &mut scenario_args
+            };
+            args.push(format!("--{}={value}", param.name));
+            values.insert(param.name.clone(), value.clone());
+        }
+        run_args.extend(scenario_args);
+        let opts = Opts::try_parse_from(&run_args).map_err(|e| {
+            // keep the error line only, without clap's usage hints
+            let e = e.to_string();
+            let e = e.lines().next().unwrap_or_default();
+            let e = e.trim_start_matches("error: ");
+            format!("invalid sweep run '{}': {e}", run_args[1..].join(" "))
+        })?;
+        for repeat in 0..repeats {
+            let mut params = values.clone();
+            if repeats > 1 {
+                params.insert("repeat".to_string(), (repeat + 1).to_string());
+            }
+            runs.push(SweepRun {
+                params,
+                repeat,
+                opts: opts.clone(),
+            });
+        }
+    }
+    Ok(runs)
+}
+
+/// Position of the subcommand in the arguments, skipping the values of the global options before
+/// it (e.g. `--output sweep`)
+fn subcommand_position(command: &clap::Command, args: &[String]) -> usize {
+    let takes_value = |arg: Option<&clap::Arg>| arg.is_some_and(|a| a.get_action().takes_values());
+    let mut i = 0;
+    while i < args.len() {
+        let token = &args[i];
+        if let Some(long) = token.strip_prefix("--") {
+            if !long.contains('=')
+                && takes_value(command.get_arguments().find(|a| a.get_long() == Some(long)))
+            {
+                i += 1;
+            }
+        } else if let Some(shorts) = token.strip_prefix('-').filter(|s| !s.is_empty()) {
+            // in a cluster of short options (e.g. `-fo <OUTPUT>`) the value follows the last one
+            for (j, short) in shorts.char_indices() {
+                let arg = command
+                    .get_arguments()
+                    .find(|a| a.get_short() == Some(short));
+                if takes_value(arg) {
+                    if j + short.len_utf8() == shorts.len() {
+                        i += 1;
+                    }
+                    break;
+                }
+            }
+        } else {
+            return i;
+        }
+        i += 1;
+    }
+    panic!("sweep command should be in the arguments")
+}
+
+/// Parameter values of a sweep run, as written to the report (e.g. `loops=4 utxo-size=1000`)
+pub(crate) fn format_params(params: &BTreeMap<String, String>) -> String {
+    params
+        .iter()
+        .map(|(name, value)| format!("{name}={value}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
@@ -0,0 +1,130 @@
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id};
+use tracing::Subscriber;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::layer::{Context, SubscriberExt};
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::Layer;
+
+/// Self time, in microseconds, of each stack of spans, keyed by its `;`-separated span labels
+static STACKS: Mutex<BTreeMap<String, u64>> = Mutex::new(BTreeMap::new());
+
+/// Timing of an open span
+struct SpanTime {
+    label: String,
+    entered: Option<Instant>,
+    busy: Duration,
+    /// Busy time of the closed child spans
+    children: Duration,
+}
+
+/// Span label, from its `label` field if set
+struct LabelVisitor(Option<String>);
+
+impl Visit for LabelVisitor {
+    fn record_str(&mut self, field: &Field, value: &str) {
+        if field.name() == "label" {
+            self.0 = Some(value.to_string());
+        }
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        if field.name() == "label" {
+            self.0 = Some(format!("{value:?}"));
+        }
+    }
+}
+
+/// Layer accumulating the self time of closed spans, i.e. their busy time minus the one of their
+/// children
+struct SelfTimeLayer;
+
+impl<S> Layer<S> for SelfTimeLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut visitor = LabelVisitor(None);
+        attrs.record(&mut visitor);
+        let metadata = attrs.metadata();
+        // spans of other crates (e.g. rgb-lib) are labelled with their module path
+        let label = visitor.0.unwrap_or_else(|| {
+            if metadata.target().starts_with(env!("CARGO_CRATE_NAME")) {
+                metadata.name().to_string()
+            } else {
+                format!("{}::{}", metadata.target(), metadata.name())
+            }
+        });
+        let span = ctx.span(id).expect("new span should exist");
+        span.extensions_mut().insert(SpanTime {
+            label,
+            entered: None,
+            busy: Duration::ZERO,
+            children: Duration::ZERO,
+        });
+    }
+
+    fn on_enter(&self, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("entered span should exist");
+        if let Some(time) = span.extensions_mut().get_mut::<SpanTime>() {
+            time.entered = Some(Instant::now());
+        };
+    }
+
+    fn on_exit(&self, id: &Id, ctx: Context<'_, S>) {
+        let span = ctx.span(id).expect("exited span should exist");
+        if let Some(time) = span.extensions_mut().get_mut::<SpanTime>() {
+            if let Some(entered) = time.entered.take() {
+                time.busy += entered.elapsed();
+            }
+        };
+    }
+
+    fn on_close(&self, id: Id, ctx: Context<'_, S>) {
+        let span = ctx.span(&id).expect("closed span should exist");
+        let Some(time) = span.extensions_mut().remove::<SpanTime>() else {
+            return;
+        };
+        if let Some(parent) = span.parent() {
+            if let Some(parent_time) = parent.extensions_mut().get_mut::<SpanTime>() {
+                parent_time.children += time.busy;
+            }
+        }
+        let mut labels: Vec<String> = span
+            .scope()
+            .skip(1)
+            .filter_map(|s| s.extensions().get::<SpanTime>().map(|t| t.label.clone()))
+            .collect();
+        labels.reverse();
+        labels.push(time.label.clone());
+        let self_time = time.busy.saturating_sub(time.children).as_micros() as u64;
+        *STACKS.lock().unwrap().entry(labels.join(";")).or_default() += self_time;
+    }
+}
+
+/// Capture the spans up to debug level, of this tool and its dependencies
+pub(crate) fn init() {
+    tracing_subscriber::registry()
+        .with(SelfTimeLayer.with_filter(LevelFilter::DEBUG))
+        .init();
+}
+
+/// Write the self time of each captured stack of spans, in the folded format of flamegraph tools
+/// (one `root;...;leaf <microseconds>` line per stack)
+pub(crate) fn write_folded(path: &Path) {
+    let folded: String = STACKS
+        .lock()
+        .unwrap()
+        .iter()
+        .map(|(stack, micros)| format!("{stack} {micros}\n"))
+        .collect();
+    fs::write(path, folded).expect("folded stacks file should have been written");
+}